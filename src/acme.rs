@@ -0,0 +1,888 @@
+use crate::certs::{Resolver, CHAIN_FILE, KEY_FILE};
+use crate::config::{AcmeChallenge, AcmeConfig};
+use crate::errors::Error;
+use crate::tls;
+use crate::x509;
+use bytes::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{error, info};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName, DnType, RcgenError};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::{any_ecdsa_type, CertifiedKey};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::{sleep, timeout};
+
+/// The ALPN protocol of TLS-ALPN-01 validation handshakes.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const ACCOUNT_KEY_FILE: &str = "account.pem";
+
+/// How often certificates are checked for renewal, and how soon that is
+/// retried when getting one failed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long pending authorizations and orders are waited on.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answers to the challenges of the orders in progress, served by the
+/// listeners while the ACME server validates them.
+#[derive(Default)]
+pub struct Challenges {
+    /// Key authorizations by token.
+    http: RwLock<HashMap<String, String>>,
+    /// Validation certificates by domain.
+    tls_alpn: RwLock<HashMap<String, CertifiedKey>>,
+}
+
+impl Challenges {
+    /// The response to an HTTP-01 validation request, `None` for any other
+    /// request.
+    pub fn http_response(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let token = req.uri().path().strip_prefix(HTTP_CHALLENGE_PATH)?;
+        let key_authorization = self.http.read().unwrap().get(token)?.clone();
+        let mut res = Response::new(Body::from(key_authorization));
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        Some(res)
+    }
+
+    /// The certificate for a TLS-ALPN-01 validation handshake.
+    pub fn tls_alpn_certificate(&self, server_name: &str) -> Option<CertifiedKey> {
+        let server_name = server_name.to_ascii_lowercase();
+        self.tls_alpn.read().unwrap().get(&server_name).cloned()
+    }
+
+    /// Serve the answer to a challenge until the returned guard is dropped.
+    fn answer(
+        &self,
+        challenge: AcmeChallenge,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<Answer<'_>, Error> {
+        let key = match challenge {
+            AcmeChallenge::Http01 => {
+                let mut http = self.http.write().unwrap();
+                http.insert(token.to_string(), key_authorization.to_string());
+                token.to_string()
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let certificate = validation_certificate(domain, key_authorization)?;
+                let domain = domain.to_ascii_lowercase();
+                let mut tls_alpn = self.tls_alpn.write().unwrap();
+                tls_alpn.insert(domain.clone(), certificate);
+                domain
+            }
+        };
+        Ok(Answer {
+            challenges: self,
+            challenge,
+            key,
+        })
+    }
+}
+
+struct Answer<'a> {
+    challenges: &'a Challenges,
+    challenge: AcmeChallenge,
+    key: String,
+}
+
+impl Drop for Answer<'_> {
+    fn drop(&mut self) {
+        match self.challenge {
+            AcmeChallenge::Http01 => {
+                self.challenges.http.write().unwrap().remove(&self.key);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                self.challenges.tls_alpn.write().unwrap().remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A self-signed certificate for `domain` carrying the digest of
+/// `key_authorization`, as TLS-ALPN-01 (RFC 8737) expects.
+fn validation_certificate(domain: &str, key_authorization: &str) -> Result<CertifiedKey, Error> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    let digest = digest(&SHA256, key_authorization.as_bytes());
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+    let cert = rcgen::Certificate::from_params(params).map_err(rcgen_error)?;
+    let key = any_ecdsa_type(&rustls::PrivateKey(cert.serialize_private_key_der()))
+        .map_err(|()| Error::Acme("could not load the validation key".into()))?;
+    let chain = vec![rustls::Certificate(
+        cert.serialize_der().map_err(rcgen_error)?,
+    )];
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+fn rcgen_error(e: RcgenError) -> Error {
+    Error::Acme(format!("could not generate a certificate: {}", e))
+}
+
+/// Gets certificates for `domains` from an ACME server, and new ones
+/// whenever they are about to expire.
+pub struct Acme {
+    config: AcmeConfig,
+    domains: Vec<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    rng: SystemRandom,
+    challenges: Arc<Challenges>,
+    certificates: Arc<Resolver>,
+}
+
+impl Acme {
+    pub fn new(
+        config: &AcmeConfig,
+        domains: Vec<String>,
+        challenges: Arc<Challenges>,
+        certificates: Arc<Resolver>,
+    ) -> Result<Acme, Error> {
+        let builder = HttpsConnectorBuilder::new();
+        let builder = match &config.ca {
+            Some(ca) => builder.with_tls_config(ca_tls_config(ca)?),
+            None => builder.with_native_roots(),
+        };
+        let client = Client::builder().build(builder.https_only().enable_http1().build());
+
+        Ok(Acme {
+            config: config.clone(),
+            domains,
+            client,
+            rng: SystemRandom::new(),
+            challenges,
+            certificates,
+        })
+    }
+
+    /// Keep the certificates current until the process exits.
+    pub async fn run(self) {
+        loop {
+            let next_check = match self.renew().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(()) => RETRY_INTERVAL,
+            };
+            sleep(next_check).await;
+        }
+    }
+
+    /// Get a certificate for every domain that has none or one about to
+    /// expire. Failures are logged.
+    async fn renew(&self) -> Result<(), ()> {
+        let due: Vec<&String> = self
+            .domains
+            .iter()
+            .filter(|domain| self.is_due(domain))
+            .collect();
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let mut account = self.account().await.map_err(|e| {
+            error!("could not open the ACME account: {}", e);
+        })?;
+        let mut result = Ok(());
+        for domain in due {
+            info!("requesting a certificate for `{}`", domain);
+            match self.issue(&mut account, domain).await {
+                Ok(()) => info!("obtained a certificate for `{}`", domain),
+                Err(e) => {
+                    error!("could not obtain a certificate for `{}`: {}", domain, e);
+                    result = Err(());
+                }
+            }
+        }
+        result
+    }
+
+    fn is_due(&self, domain: &str) -> bool {
+        let path = self.config.storage.join(domain).join(CHAIN_FILE);
+        let not_after = fs::read_to_string(path)
+            .ok()
+            .and_then(|pem| x509::pem_blocks(&pem).ok())
+            .and_then(|blocks| blocks.into_iter().next())
+            .and_then(|(_, der)| x509::not_after(&der));
+        not_after.is_none_or(|not_after| not_after < SystemTime::now() + self.config.renew_before)
+    }
+
+    /// Register the account key, or look up the account it already has.
+    async fn account(&self) -> Result<Account, Error> {
+        let key = self.account_key()?;
+        let req = Request::get(&self.config.directory).body(Body::empty())?;
+        let directory: Directory = self.send(req).await?.parse()?;
+
+        let public_key = key.public_key().as_ref();
+        // An uncompressed point: 0x04, then x and y.
+        let (x, y) = public_key[1..].split_at(32);
+        let jwk = json!({"crv": "P-256", "kty": "EC", "x": base64url(x), "y": base64url(y)});
+        // The members of a JWK thumbprint (RFC 7638) are sorted, as
+        // `serde_json` keeps them.
+        let thumbprint = base64url(digest(&SHA256, jwk.to_string().as_bytes()).as_ref());
+
+        let mut account = Account {
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            directory,
+            nonce: None,
+        };
+        let new_account = account.directory.new_account.clone();
+        let payload = json!({"termsOfServiceAgreed": true, "contact": self.config.contact});
+        let reply = self
+            .post(&mut account, &new_account, Some(&payload))
+            .await?;
+        account.kid = Some(reply.location()?);
+        Ok(account)
+    }
+
+    /// The account key, generated and saved on first use.
+    fn account_key(&self) -> Result<EcdsaKeyPair, Error> {
+        let path = self.config.storage.join(ACCOUNT_KEY_FILE);
+        let pkcs8 = match fs::read_to_string(&path) {
+            Ok(pem) => x509::pem_blocks(&pem)
+                .ok()
+                .and_then(|blocks| blocks.into_iter().find(|(label, _)| label == "PRIVATE KEY"))
+                .map(|(_, der)| der)
+                .ok_or_else(|| {
+                    Error::Acme(format!("`{}` holds no PKCS#8 private key", path.display()))
+                })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let pkcs8 =
+                    EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &self.rng)
+                        .map_err(|_| Error::Acme("could not generate an account key".into()))?;
+                fs::create_dir_all(&self.config.storage)?;
+                write_private(&path, &x509::pem("PRIVATE KEY", pkcs8.as_ref()))?;
+                info!("created ACME account key `{}`", path.display());
+                pkcs8.as_ref().to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8)
+            .map_err(|e| Error::Acme(format!("`{}` is not a P-256 key: {}", path.display(), e)))
+    }
+
+    /// Order a certificate for `domain`, prove we control it, and save and
+    /// serve the certificate we get.
+    async fn issue(&self, account: &mut Account, domain: &str) -> Result<(), Error> {
+        let new_order = account.directory.new_order.clone();
+        let payload = json!({"identifiers": [{"type": "dns", "value": domain}]});
+        let reply = self.post(account, &new_order, Some(&payload)).await?;
+        let order_url = reply.location()?;
+        let order: Order = reply.parse()?;
+        for authorization in &order.authorizations {
+            self.authorize(account, authorization).await?;
+        }
+
+        let mut params = CertificateParams::new(vec![domain.to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, domain);
+        let key = rcgen::Certificate::from_params(params).map_err(rcgen_error)?;
+        let csr = key.serialize_request_der().map_err(rcgen_error)?;
+        let payload = json!({ "csr": base64url(&csr) });
+        self.post(account, &order.finalize, Some(&payload)).await?;
+
+        let order: Order = self.poll(account, &order_url).await?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate)) => certificate,
+            (status, _) => {
+                return Err(Error::Acme(format!(
+                    "order ended up {}{}",
+                    status,
+                    order.error.map(|e| format!(": {}", e)).unwrap_or_default()
+                )))
+            }
+        };
+        let chain = self.post(account, &certificate, None).await?.body;
+        let chain = String::from_utf8(chain.to_vec())
+            .map_err(|_| Error::Acme("certificate chain is not PEM".into()))?;
+        self.store(domain, &chain, &key.serialize_private_key_pem())
+    }
+
+    /// Answer the challenge of an authorization and wait for the server to
+    /// check it.
+    async fn authorize(&self, account: &mut Account, url: &str) -> Result<(), Error> {
+        let authorization: Authorization = self.post(account, url, None).await?.parse()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let kind = self.config.challenge.name();
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == kind)
+            .ok_or_else(|| {
+                Error::Acme(format!("no {} challenge offered for `{}`", kind, domain))
+            })?;
+
+        let key_authorization = format!("{}.{}", challenge.token, account.thumbprint);
+        let _answer = self.challenges.answer(
+            self.config.challenge,
+            &domain,
+            &challenge.token,
+            &key_authorization,
+        )?;
+        self.post(account, &challenge.url, Some(&json!({}))).await?;
+
+        let authorization: Authorization = self.poll(account, url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let reason = authorization
+            .challenges
+            .into_iter()
+            .find_map(|challenge| challenge.error)
+            .map_or(authorization.status, |e| e.to_string());
+        Err(Error::Acme(format!(
+            "validation of `{}` failed: {}",
+            domain, reason
+        )))
+    }
+
+    /// Save the certificate where `Certificates::load` finds it after a
+    /// restart, then serve it.
+    fn store(&self, domain: &str, chain: &str, key: &str) -> Result<(), Error> {
+        let dir = self.config.storage.join(domain);
+        fs::create_dir_all(&dir)?;
+        // Both are written out before either is replaced, and the chain goes
+        // last, so a reader never sees a new chain with the old key. One that
+        // sees the new key with the old chain rejects the pair as mismatched
+        // and tries again after the chain is in place.
+        let key_temp = write_temp(&dir.join(KEY_FILE), key)?;
+        let chain_temp = write_temp(&dir.join(CHAIN_FILE), chain)?;
+        fs::rename(key_temp, dir.join(KEY_FILE))?;
+        fs::rename(chain_temp, dir.join(CHAIN_FILE))?;
+        fs::File::open(&dir)?.sync_all()?;
+
+        let key = tls::load_certified_key(domain, &dir.join(CHAIN_FILE), &dir.join(KEY_FILE))?;
+        self.certificates.insert(domain, key)
+    }
+
+    /// POST-as-GET `url` until what it returns is no longer pending.
+    async fn poll<T: DeserializeOwned>(
+        &self,
+        account: &mut Account,
+        url: &str,
+    ) -> Result<T, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let reply = self.post(account, url, None).await?;
+            let status: Status = reply.parse()?;
+            if !matches!(status.status.as_str(), "pending" | "processing") {
+                return reply.parse();
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        Err(Error::Acme(format!("`{}` is still pending", url)))
+    }
+
+    /// Send a signed request, or a POST-as-GET without a `payload`.
+    async fn post(
+        &self,
+        account: &mut Account,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<Reply, Error> {
+        let mut retried = false;
+        loop {
+            let nonce = match account.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce(account).await?,
+            };
+            let body = account.sign(&self.rng, url, &nonce, payload)?;
+            let req = Request::post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body))?;
+            let reply = self.send(req).await?;
+            account.nonce = reply.nonce.clone();
+            if reply.status.is_success() {
+                return Ok(reply);
+            }
+
+            let problem: Problem = serde_json::from_slice(&reply.body).unwrap_or_default();
+            // Nonces go stale, the server hands out a fresh one to retry with.
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(Error::Acme(format!(
+                "`{}` answered {}: {}",
+                url, reply.status, problem
+            )));
+        }
+    }
+
+    async fn new_nonce(&self, account: &Account) -> Result<String, Error> {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(&account.directory.new_nonce)
+            .body(Body::empty())?;
+        self.send(req)
+            .await?
+            .nonce
+            .ok_or_else(|| Error::Acme("server sent no nonce".into()))
+    }
+
+    async fn send(&self, req: Request<Body>) -> Result<Reply, Error> {
+        let url = req.uri().to_string();
+        let failed =
+            |e: &dyn fmt::Display| Error::Acme(format!("request to `{}` failed: {}", url, e));
+        let res = match timeout(REQUEST_TIMEOUT, self.client.request(req)).await {
+            Ok(res) => res.map_err(|e| failed(&e))?,
+            Err(_) => return Err(failed(&"timed out")),
+        };
+        let header = |name| {
+            res.headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let nonce = header("replay-nonce");
+        let location = header(header::LOCATION.as_str());
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(|e| failed(&e))?;
+        Ok(Reply {
+            status,
+            nonce,
+            location,
+            body,
+        })
+    }
+}
+
+/// A TLS config trusting only the certificates in the PEM file `ca`.
+fn ca_tls_config(ca: &Path) -> Result<rustls_client::ClientConfig, Error> {
+    let invalid =
+        |reason: String| Error::Config(format!("`acme.ca` `{}` {}", ca.display(), reason));
+    let pem = fs::read_to_string(ca).map_err(|e| invalid(format!("could not be read: {}", e)))?;
+    let blocks = x509::pem_blocks(&pem).map_err(|e| invalid(format!("is not valid PEM: {}", e)))?;
+    let mut roots = rustls_client::RootCertStore::empty();
+    for (_, der) in blocks.iter().filter(|(label, _)| label == "CERTIFICATE") {
+        roots
+            .add(&rustls_client::Certificate(der.clone()))
+            .map_err(|e| invalid(format!("holds an invalid certificate: {}", e)))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid("holds no certificates".into()));
+    }
+    Ok(rustls_client::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Write a file only we can read, replacing what was there at once.
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let temp = write_temp(path, contents)?;
+    fs::rename(temp, path)
+}
+
+/// Write a file only we can read next to `path`, to be renamed over it.
+fn write_temp(path: &Path, contents: &str) -> io::Result<PathBuf> {
+    let mut temp = PathBuf::from(path);
+    temp.set_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(temp)
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+struct Account {
+    key: EcdsaKeyPair,
+    jwk: Value,
+    thumbprint: String,
+    /// The account URL, once registered.
+    kid: Option<String>,
+    directory: Directory,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// A JWS (RFC 7515) of `payload`, in the flattened JSON form.
+    fn sign(
+        &self,
+        rng: &SystemRandom,
+        url: &str,
+        nonce: &str,
+        payload: Option<&Value>,
+    ) -> Result<String, Error> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = base64url(protected.to_string().as_bytes());
+        let payload = payload.map_or_else(String::new, |payload| {
+            base64url(payload.to_string().as_bytes())
+        });
+        let signature = self
+            .key
+            .sign(rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| Error::Acme("could not sign request".into()))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64url(signature.as_ref()),
+        })
+        .to_string())
+    }
+}
+
+struct Reply {
+    status: StatusCode,
+    nonce: Option<String>,
+    location: Option<String>,
+    body: Bytes,
+}
+
+impl Reply {
+    fn parse<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| Error::Acme(format!("unexpected response: {}", e)))
+    }
+
+    fn location(&self) -> Result<String, Error> {
+        self.location
+            .clone()
+            .ok_or_else(|| Error::Acme("response has no location".into()))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+/// An error reported by the ACME server (RFC 7807).
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    detail: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{} ({})", detail, self.kind),
+            None => f.write_str(&self.kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acme, Challenges, HTTP_CHALLENGE_PATH};
+    use crate::certs::{Certificates, Resolver, CHAIN_FILE};
+    use crate::config::{AcmeChallenge, AcmeConfig};
+    use crate::tls::self_signed_certificate;
+    use crate::x509;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, Request, Response, StatusCode};
+    use rcgen::{BasicConstraints, CertificateParams, CertificateSigningRequest, IsCa};
+    use serde_json::{json, Value};
+    use std::convert::Infallible;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// An ACME server issuing certificates for `example.com` with HTTP-01,
+    /// which only checks that the challenge is answered.
+    struct Server {
+        base: String,
+        challenges: Arc<Challenges>,
+        ca: rcgen::Certificate,
+        validated: AtomicBool,
+        issued: Mutex<Option<Vec<u8>>>,
+        /// The method and path of every request.
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl Server {
+        async fn handle(&self, req: Request<Body>) -> Response<Body> {
+            let path = req.uri().path().to_string();
+            let request = format!("{} {}", req.method(), path);
+            self.requests.lock().unwrap().push(request);
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let payload = serde_json::from_slice::<Value>(&body)
+                .ok()
+                .and_then(|jws| {
+                    base64::decode_config(jws["payload"].as_str()?, base64::URL_SAFE_NO_PAD).ok()
+                })
+                .and_then(|payload| serde_json::from_slice::<Value>(&payload).ok());
+
+            let url = |path: &str| format!("{}{}", self.base, path);
+            let order = |status: &str| {
+                json!({
+                    "status": status,
+                    "authorizations": [url("/authz/1")],
+                    "finalize": url("/finalize/1"),
+                    "certificate": url("/cert/1"),
+                })
+            };
+            let (status, location, body) = match path.as_str() {
+                "/directory" => (
+                    StatusCode::OK,
+                    None,
+                    json!({
+                        "newNonce": url("/nonce"),
+                        "newAccount": url("/account"),
+                        "newOrder": url("/order"),
+                    })
+                    .to_string(),
+                ),
+                "/nonce" => (StatusCode::OK, None, String::new()),
+                "/account" => (StatusCode::CREATED, Some(url("/account/1")), "{}".into()),
+                "/order" => (
+                    StatusCode::CREATED,
+                    Some(url("/order/1")),
+                    order("pending").to_string(),
+                ),
+                "/authz/1" => {
+                    let validated = self.validated.load(Ordering::SeqCst);
+                    let authorization = json!({
+                        "status": if validated { "valid" } else { "pending" },
+                        "identifier": {"type": "dns", "value": "example.com"},
+                        "challenges": [
+                            {"type": "http-01", "url": url("/challenge/1"), "token": "token"},
+                        ],
+                    });
+                    (StatusCode::OK, None, authorization.to_string())
+                }
+                "/challenge/1" => {
+                    let path = format!("{}token", HTTP_CHALLENGE_PATH);
+                    let req = Request::get(path).body(Body::empty()).unwrap();
+                    if let Some(res) = self.challenges.http_response(&req) {
+                        let answer = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                        self.validated
+                            .store(answer.starts_with(b"token."), Ordering::SeqCst);
+                    }
+                    (StatusCode::OK, None, "{}".into())
+                }
+                "/finalize/1" => {
+                    let csr = payload.as_ref().and_then(|payload| payload["csr"].as_str());
+                    let csr = base64::decode_config(csr.unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+                    let csr = CertificateSigningRequest::from_der(&csr).unwrap();
+                    let cert = csr.serialize_der_with_signer(&self.ca).unwrap();
+                    *self.issued.lock().unwrap() = Some(cert);
+                    (StatusCode::OK, None, order("processing").to_string())
+                }
+                "/order/1" => (StatusCode::OK, None, order("valid").to_string()),
+                "/cert/1" => {
+                    let cert = self.issued.lock().unwrap().clone().unwrap();
+                    let chain = x509::pem("CERTIFICATE", &cert)
+                        + &x509::pem("CERTIFICATE", &self.ca.serialize_der().unwrap());
+                    (StatusCode::OK, None, chain)
+                }
+                _ => (StatusCode::NOT_FOUND, None, String::new()),
+            };
+            let mut res = Response::builder()
+                .status(status)
+                .header("replay-nonce", "nonce");
+            if let Some(location) = location {
+                res = res.header("location", location);
+            }
+            res.body(Body::from(body)).unwrap()
+        }
+    }
+
+    /// Start a `Server` answering over HTTPS, and return it with its TLS
+    /// certificate.
+    async fn serve(challenges: Arc<Challenges>) -> (Arc<Server>, Vec<u8>) {
+        let tls = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls_cert = tls.serialize_der().unwrap();
+        let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        config
+            .set_single_cert(
+                vec![rustls::Certificate(tls_cert.clone())],
+                rustls::PrivateKey(tls.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Arc::new(Server {
+            base: format!(
+                "https://localhost:{}",
+                listener.local_addr().unwrap().port()
+            ),
+            challenges,
+            ca: rcgen::Certificate::from_params(params).unwrap(),
+            validated: AtomicBool::new(false),
+            issued: Mutex::new(None),
+            requests: Mutex::new(Vec::new()),
+        });
+        let serving = server.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let (acceptor, server) = (acceptor.clone(), serving.clone());
+                tokio::spawn(async move {
+                    let tls = acceptor.accept(tcp).await.unwrap();
+                    let service = service_fn(move |req| {
+                        let server = server.clone();
+                        async move { Ok::<_, Infallible>(server.handle(req).await) }
+                    });
+                    let _ = Http::new().serve_connection(tls, service).await;
+                });
+            }
+        });
+        (server, tls_cert)
+    }
+
+    #[tokio::test]
+    async fn orders_certificates_and_serves_them() {
+        let dir = std::env::temp_dir().join(format!("proxy-acme-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let challenges = Arc::new(Challenges::default());
+        let (server, tls_cert) = serve(challenges.clone()).await;
+        fs::write(dir.join("ca.pem"), x509::pem("CERTIFICATE", &tls_cert)).unwrap();
+        let config = AcmeConfig {
+            directory: format!("{}/directory", server.base),
+            contact: Vec::new(),
+            domains: vec!["example.com".to_string()],
+            challenge: AcmeChallenge::Http01,
+            storage: dir.join("storage"),
+            ca: Some(dir.join("ca.pem")),
+            renew_before: Duration::from_secs(24 * 60 * 60),
+        };
+
+        // Serving a certificate of its own until the ACME one replaces it.
+        let mut certificates = Certificates::default();
+        let old = self_signed_certificate(vec!["example.com".to_string()]).unwrap();
+        certificates.insert("example.com", old).unwrap();
+        let certificates = Arc::new(Resolver::new(certificates));
+        let domains = config.domains.clone();
+        let acme = Acme::new(&config, domains, challenges.clone(), certificates.clone()).unwrap();
+        assert!(acme.is_due("example.com"));
+        acme.renew().await.unwrap();
+
+        assert_eq!(
+            *server.requests.lock().unwrap(),
+            [
+                "GET /directory",
+                "HEAD /nonce",
+                "POST /account",
+                "POST /order",
+                "POST /authz/1",
+                "POST /challenge/1",
+                "POST /authz/1",
+                "POST /finalize/1",
+                "POST /order/1",
+                "POST /cert/1",
+            ]
+        );
+        let issued = server.issued.lock().unwrap().clone().unwrap();
+        let served = certificates.get("example.com").unwrap();
+        assert_eq!(served.cert[0].0, issued);
+        assert!(dir.join("storage/example.com").join(CHAIN_FILE).exists());
+        assert!(!acme.is_due("example.com"));
+        // The challenge is only answered while the order is in progress.
+        let path = format!("{}token", HTTP_CHALLENGE_PATH);
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        assert!(challenges.http_response(&req).is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn serves_challenges_while_answered() {
+        let challenges = Challenges::default();
+        let req = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+        let url = format!("{}token", HTTP_CHALLENGE_PATH);
+
+        let answer = challenges
+            .answer(AcmeChallenge::Http01, "example.com", "token", "token.thumb")
+            .unwrap();
+        assert!(challenges.http_response(&req(&url)).is_some());
+        assert!(challenges.http_response(&req("/token")).is_none());
+        let _tls = challenges
+            .answer(
+                AcmeChallenge::TlsAlpn01,
+                "Example.com",
+                "token",
+                "token.thumb",
+            )
+            .unwrap();
+        assert!(challenges.tls_alpn_certificate("example.com").is_some());
+
+        drop(answer);
+        assert!(challenges.http_response(&req(&url)).is_none());
+    }
+}
@@ -0,0 +1,372 @@
+use crate::config::{BalanceConfig, BalanceStrategy, HashKey};
+use crate::upstream::{Member, Pool};
+use hyper::{header, HeaderMap};
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// What a balancer may look at to pick a member for a request.
+pub struct RequestContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub headers: &'a HeaderMap,
+    /// Members an earlier attempt of the request already failed on.
+    pub tried: &'a [Arc<Member>],
+}
+
+impl RequestContext<'_> {
+    /// Whether `member` is available and wasn't tried yet.
+    pub fn allows(&self, member: &Arc<Member>) -> bool {
+        member.is_available() && !self.tried.iter().any(|tried| Arc::ptr_eq(tried, member))
+    }
+}
+
+/// A strategy for spreading requests over the members of a pool.
+pub trait Balancer: Send + Sync + Debug {
+    /// Pick the member of `pool` to send the next request to, skipping
+    /// members `ctx` doesn't allow. `None` if no member is left.
+    fn pick(&self, pool: &Pool, ctx: &RequestContext<'_>) -> Option<Arc<Member>>;
+}
+
+/// Build the balancer configured for a route to `pool`.
+pub fn from_config(config: &BalanceConfig, pool: &Pool) -> Box<dyn Balancer> {
+    match config {
+        BalanceConfig::Strategy(BalanceStrategy::RoundRobin) => Box::new(RoundRobin::default()),
+        BalanceConfig::Strategy(BalanceStrategy::WeightedRoundRobin) => {
+            Box::new(WeightedRoundRobin::default())
+        }
+        BalanceConfig::Strategy(BalanceStrategy::LeastRequests) => {
+            Box::new(LeastRequests::default())
+        }
+        BalanceConfig::Strategy(BalanceStrategy::RandomTwoChoices) => Box::new(RandomTwoChoices),
+        BalanceConfig::Hash { hash, name } => {
+            Box::new(ConsistentHash::new(pool, *hash, name.clone()))
+        }
+    }
+}
+
+/// Every available member in turn, ignoring weights.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl Balancer for RoundRobin {
+    fn pick(&self, pool: &Pool, ctx: &RequestContext<'_>) -> Option<Arc<Member>> {
+        let len = pool.members.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|i| &pool.members[(start + i) % len])
+            .find(|member| ctx.allows(member))
+            .cloned()
+    }
+}
+
+/// Nginx's smooth weighted round robin: each member gets picked in
+/// proportion to its weight, without bursts to the heaviest one.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    current: Mutex<Vec<i64>>,
+}
+
+impl Balancer for WeightedRoundRobin {
+    fn pick(&self, pool: &Pool, ctx: &RequestContext<'_>) -> Option<Arc<Member>> {
+        let mut current = self.current.lock().unwrap();
+        current.resize(pool.members.len(), 0);
+
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (index, member) in pool.members.iter().enumerate() {
+            if !ctx.allows(member) {
+                continue;
+            }
+            let weight = i64::from(member.weight);
+            current[index] += weight;
+            total += weight;
+            if best.is_none_or(|best| current[index] > current[best]) {
+                best = Some(index);
+            }
+        }
+
+        let best = best?;
+        current[best] -= total;
+        Some(pool.members[best].clone())
+    }
+}
+
+/// The member with the fewest outstanding requests, rotating the starting
+/// point so ties don't all land on the first member.
+#[derive(Debug, Default)]
+pub struct LeastRequests {
+    offset: AtomicUsize,
+}
+
+impl Balancer for LeastRequests {
+    fn pick(&self, pool: &Pool, ctx: &RequestContext<'_>) -> Option<Arc<Member>> {
+        let len = pool.members.len();
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|i| &pool.members[(offset + i) % len])
+            .filter(|member| ctx.allows(member))
+            .min_by_key(|member| member.outstanding())
+            .cloned()
+    }
+}
+
+/// The less busy of two members picked at random.
+#[derive(Debug)]
+pub struct RandomTwoChoices;
+
+impl Balancer for RandomTwoChoices {
+    fn pick(&self, pool: &Pool, ctx: &RequestContext<'_>) -> Option<Arc<Member>> {
+        let available: Vec<&Arc<Member>> = pool
+            .members
+            .iter()
+            .filter(|member| ctx.allows(member))
+            .collect();
+        let len = available.len();
+        if len < 2 {
+            return available.first().copied().cloned();
+        }
+
+        let mut rng = rand::thread_rng();
+        let a = rng.gen_range(0..len);
+        let b = (a + rng.gen_range(1..len)) % len;
+        let (a, b) = (available[a], available[b]);
+        Some(
+            if a.outstanding() <= b.outstanding() {
+                a
+            } else {
+                b
+            }
+            .clone(),
+        )
+    }
+}
+
+/// Points each member gets on the hash ring per unit of weight.
+const POINTS_PER_WEIGHT: u32 = 100;
+
+/// A hash ring keyed on the client IP, a header or a cookie, so the same
+/// client keeps hitting the same member while the pool doesn't change.
+/// Requests without the key go to a random member, and requests whose
+/// member is unavailable to the next one on the ring.
+#[derive(Debug)]
+pub struct ConsistentHash {
+    ring: Vec<(u64, usize)>,
+    key: HashKey,
+    name: Option<String>,
+}
+
+impl ConsistentHash {
+    pub fn new(pool: &Pool, key: HashKey, name: Option<String>) -> ConsistentHash {
+        let mut ring = Vec::new();
+        for (index, member) in pool.members.iter().enumerate() {
+            let url = member.url.to_string();
+            for point in 0..member.weight * POINTS_PER_WEIGHT {
+                ring.push((hash(&(&url, point)), index));
+            }
+        }
+        ring.sort_unstable();
+
+        ConsistentHash { ring, key, name }
+    }
+
+    fn key(&self, ctx: &RequestContext<'_>) -> Option<u64> {
+        let name = self.name.as_deref().unwrap_or_default();
+        match self.key {
+            HashKey::ClientIp => ctx.client_ip.map(|ip| hash(&ip)),
+            HashKey::Header => ctx.headers.get(name).map(|value| hash(&value.as_bytes())),
+            HashKey::Cookie => ctx
+                .headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(cookie, _)| *cookie == name)
+                .map(|(_, value)| hash(&value)),
+        }
+    }
+}
+
+impl Balancer for ConsistentHash {
+    fn pick(&self, pool: &Pool, ctx: &RequestContext<'_>) -> Option<Arc<Member>> {
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        let len = self.ring.len();
+        let start = match self.key(ctx) {
+            Some(key) => match self.ring.binary_search_by_key(&key, |&(point, _)| point) {
+                Ok(point) | Err(point) => point,
+            },
+            None => rand::thread_rng().gen_range(0..len),
+        };
+        (0..len)
+            .filter_map(|i| pool.members.get(self.ring[(start + i) % len].1))
+            .find(|member| ctx.allows(member))
+            .cloned()
+    }
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Balancer, ConsistentHash, LeastRequests, RandomTwoChoices, RequestContext, RoundRobin,
+        WeightedRoundRobin,
+    };
+    use crate::config::{HashKey, UpstreamConfig};
+    use crate::upstream::Pool;
+    use hyper::HeaderMap;
+
+    fn pool() -> Pool {
+        let config: UpstreamConfig = toml::from_str(
+            r#"
+            servers = [
+                { url = "http://a", weight = 3 },
+                { url = "http://b" },
+            ]
+            "#,
+        )
+        .unwrap();
+        Pool::new("app", &config).unwrap()
+    }
+
+    fn picks(balancer: &dyn Balancer, pool: &Pool, ctx: &RequestContext<'_>) -> String {
+        (0..8)
+            .map(|_| {
+                balancer
+                    .pick(pool, ctx)
+                    .unwrap()
+                    .url
+                    .host()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn weighted_round_robin_is_smooth() {
+        let pool = pool();
+        let headers = HeaderMap::new();
+        let ctx = RequestContext {
+            client_ip: None,
+            headers: &headers,
+            tried: &[],
+        };
+        assert_eq!(
+            "aabaaaba",
+            picks(&WeightedRoundRobin::default(), &pool, &ctx)
+        );
+    }
+
+    #[test]
+    fn consistent_hash_is_sticky() {
+        let pool = pool();
+        let balancer = ConsistentHash::new(&pool, HashKey::Cookie, Some("session".into()));
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "theme=dark; session=1234".parse().unwrap());
+        let ctx = RequestContext {
+            client_ip: None,
+            headers: &headers,
+            tried: &[],
+        };
+        let first = picks(&balancer, &pool, &ctx);
+        assert!(first == "aaaaaaaa" || first == "bbbbbbbb");
+    }
+
+    #[test]
+    fn least_requests_follows_outstanding_requests() {
+        let pool = pool();
+        let headers = HeaderMap::new();
+        let ctx = RequestContext {
+            client_ip: None,
+            headers: &headers,
+            tried: &[],
+        };
+        let balancer = LeastRequests::default();
+
+        let a = pool.members[0].start_request();
+        assert_eq!("bbbbbbbb", picks(&balancer, &pool, &ctx));
+        let b = [
+            pool.members[1].start_request(),
+            pool.members[1].start_request(),
+        ];
+        assert_eq!("aaaaaaaa", picks(&balancer, &pool, &ctx));
+        drop(b);
+        assert_eq!(pool.members[1].outstanding(), 0);
+        assert_eq!("bbbbbbbb", picks(&balancer, &pool, &ctx));
+        // Ties take turns.
+        drop(a);
+        assert_eq!("abababab", picks(&balancer, &pool, &ctx));
+    }
+
+    #[test]
+    fn random_two_choices_skips_unavailable_members() {
+        let config: UpstreamConfig = toml::from_str(
+            r#"
+            servers = [
+                { url = "http://a" },
+                { url = "http://b" },
+                { url = "http://c" },
+                { url = "http://d" },
+            ]
+            outlier_detection = { consecutive_failures = 1, max_ejected_percent = 50 }
+            "#,
+        )
+        .unwrap();
+        let pool = Pool::new("app", &config).unwrap();
+        let headers = HeaderMap::new();
+        let ctx = RequestContext {
+            client_ip: None,
+            headers: &headers,
+            tried: &[],
+        };
+        pool.report(&pool.members[0], false);
+        assert!(pool.members[0].is_ejected());
+        pool.members[1].set_healthy(false);
+
+        let seen: String = (0..4)
+            .map(|_| picks(&RandomTwoChoices, &pool, &ctx))
+            .collect();
+        assert!(seen.chars().all(|host| host == 'c' || host == 'd'));
+        // With two members left, both are always compared.
+        let _c = pool.members[2].start_request();
+        assert_eq!("dddddddd", picks(&RandomTwoChoices, &pool, &ctx));
+
+        pool.members[2].set_healthy(false);
+        pool.members[3].set_healthy(false);
+        assert!(RandomTwoChoices.pick(&pool, &ctx).is_none());
+    }
+
+    #[test]
+    fn skips_unhealthy_members() {
+        let pool = pool();
+        let headers = HeaderMap::new();
+        let ctx = RequestContext {
+            client_ip: None,
+            headers: &headers,
+            tried: &[],
+        };
+        pool.members[0].set_healthy(false);
+        assert_eq!("bbbbbbbb", picks(&RoundRobin::default(), &pool, &ctx));
+        assert_eq!(
+            "bbbbbbbb",
+            picks(&WeightedRoundRobin::default(), &pool, &ctx)
+        );
+
+        pool.members[1].set_healthy(false);
+        assert!(RoundRobin::default().pick(&pool, &ctx).is_none());
+    }
+}
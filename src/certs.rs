@@ -0,0 +1,430 @@
+use crate::config::Config;
+use crate::errors::Error;
+use crate::tls;
+use log::{info, warn};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientHello, ResolvesServerCert};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// Files tried, in order, for the certificate chain of a domain directory.
+const CERT_FILES: [&str; 2] = [CHAIN_FILE, "cert.pem"];
+pub const CHAIN_FILE: &str = "fullchain.pem";
+pub const KEY_FILE: &str = "privkey.pem";
+
+/// Certificates by the name they are served for. A name may be a wildcard
+/// like `*.example.com`, matching exactly one more label, and is only used
+/// when no certificate has the exact name. Handshakes without a name, and
+/// unless `reject_unknown_names` is set those for a name without a
+/// certificate, get the default certificate if there is one.
+#[derive(Default)]
+pub struct Certificates {
+    names: HashMap<String, CertifiedKey>,
+    default: Option<CertifiedKey>,
+    reject_unknown_names: bool,
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificates")
+            .field("names", &self.names.keys())
+            .field("default", &self.default.is_some())
+            .field("reject_unknown_names", &self.reject_unknown_names)
+            .finish()
+    }
+}
+
+impl Certificates {
+    /// Load the certificates of `config`: every domain directory in the
+    /// certificate directory and in the ACME storage, then the
+    /// `[[certificates]]` entries, which win for names in both. Broken
+    /// domain directories are logged and skipped, broken entries are an
+    /// error.
+    pub fn load(config: &Config) -> Result<Certificates, Error> {
+        let mut certificates = Certificates::default();
+        for dir in domain_dirs(config) {
+            certificates.scan_dir(&dir)?;
+        }
+        for cert in &config.certificates {
+            let key = tls::load_certified_key(&cert.domain, &cert.cert, &cert.key)?;
+            certificates.insert(&cert.domain, key)?;
+        }
+
+        certificates.reject_unknown_names = config.tls.reject_unknown_names;
+        if let Some(default) = &config.tls.default_certificate {
+            let key = tls::load_certified_key("default", &default.cert, &default.key)?;
+            certificates.default = Some(key);
+        } else if config.tls.self_signed_default {
+            let names = config.hosts.iter().flat_map(|host| &host.names).cloned();
+            let key = tls::self_signed_certificate(
+                std::iter::once("localhost".to_string())
+                    .chain(names)
+                    .collect(),
+            )?;
+            warn!("serving a self-signed default certificate, which no client will trust");
+            certificates.default = Some(key);
+        }
+        Ok(certificates)
+    }
+
+    /// Add the certificate in every `<dir>/<domain>/` that has one.
+    pub fn scan_dir(&mut self, dir: &Path) -> Result<(), Error> {
+        let entries = fs::read_dir(dir).map_err(|e| {
+            Error::Config(format!(
+                "could not read certificate directory `{}`: {}",
+                dir.display(),
+                e
+            ))
+        })?;
+
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("skipping entry of `{}`: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            if !path.is_dir() {
+                continue;
+            }
+            let domain = match path.file_name().and_then(|name| name.to_str()) {
+                Some(domain) => domain.to_string(),
+                None => continue,
+            };
+
+            match load_domain_dir(&domain, &path).and_then(|key| self.insert(&domain, key)) {
+                Ok(()) => info!("loaded certificate for `{}`", domain),
+                Err(e) => warn!("skipping {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve `key` for `name`, replacing any certificate it had.
+    pub fn insert(&mut self, name: &str, key: CertifiedKey) -> Result<(), Error> {
+        let name = normalize(name);
+        if !is_valid_name(&name) {
+            return Err(Error::Certificate {
+                domain: name,
+                reason: "is not a valid DNS name or wildcard".into(),
+            });
+        }
+        self.names.insert(name, key);
+        Ok(())
+    }
+
+    /// Stop serving a certificate for `name`, returning whether it had one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.names.remove(&normalize(name)).is_some()
+    }
+
+    /// The certificate for the server name a client asked for.
+    pub fn get(&self, server_name: &str) -> Option<&CertifiedKey> {
+        let name = normalize(server_name);
+        self.names.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            self.names.get(&format!("*.{}", parent))
+        })
+    }
+
+    /// The certificate a handshake is served, given the server name it
+    /// asked for.
+    pub fn resolve(&self, server_name: Option<&str>) -> Option<&CertifiedKey> {
+        match server_name {
+            Some(name) if self.reject_unknown_names => self.get(name),
+            Some(name) => self.get(name).or(self.default.as_ref()),
+            None => self.default.as_ref(),
+        }
+    }
+}
+
+/// The certificates handshakes are served from. Certificates can be added,
+/// replaced and removed while the listeners keep running, each change
+/// applying to the handshakes started after it.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    certificates: RwLock<Certificates>,
+}
+
+impl Resolver {
+    pub fn new(certificates: Certificates) -> Resolver {
+        Resolver {
+            certificates: RwLock::new(certificates),
+        }
+    }
+
+    pub fn get(&self, server_name: &str) -> Option<CertifiedKey> {
+        self.certificates.read().unwrap().get(server_name).cloned()
+    }
+
+    /// The certificate a handshake is served, given the server name it
+    /// asked for.
+    pub fn resolve_name(&self, server_name: Option<&str>) -> Option<CertifiedKey> {
+        self.certificates
+            .read()
+            .unwrap()
+            .resolve(server_name)
+            .cloned()
+    }
+
+    pub fn rejects_unknown_names(&self) -> bool {
+        self.certificates.read().unwrap().reject_unknown_names
+    }
+
+    /// Serve `key` for `name`, replacing any certificate it had.
+    pub fn insert(&self, name: &str, key: CertifiedKey) -> Result<(), Error> {
+        self.certificates.write().unwrap().insert(name, key)
+    }
+
+    /// Stop serving a certificate for `name`, returning whether it had one.
+    pub fn remove(&self, name: &str) -> bool {
+        self.certificates.write().unwrap().remove(name)
+    }
+
+    /// Swap in a whole new set of certificates at once, as on reload.
+    pub fn replace(&self, certificates: Certificates) {
+        *self.certificates.write().unwrap() = certificates;
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.resolve_name(client_hello.server_name().map(<&str>::from))
+    }
+}
+
+/// When the files of each domain directory last changed.
+type Stamps = HashMap<PathBuf, Vec<Option<SystemTime>>>;
+
+/// Notices certificates being added to, changed in or removed from the
+/// domain directories, so they are served without a reload. Names with a
+/// `[[certificates]]` entry are left alone, as that entry wins.
+pub struct Watch {
+    seen: Stamps,
+}
+
+impl Watch {
+    pub fn new(config: &Config) -> Watch {
+        Watch {
+            seen: stamps(config),
+        }
+    }
+
+    /// Apply the changes made since the last check to `resolver`.
+    pub fn check(&mut self, config: &Config, resolver: &Resolver) {
+        let pinned: HashSet<String> = config
+            .certificates
+            .iter()
+            .map(|cert| normalize(&cert.domain))
+            .collect();
+        let current = stamps(config);
+
+        for (dir, stamp) in &current {
+            let domain = domain_name(dir);
+            if self.seen.get(dir) == Some(stamp) || pinned.contains(&normalize(domain)) {
+                continue;
+            }
+            match load_domain_dir(domain, dir).and_then(|key| resolver.insert(domain, key)) {
+                Ok(()) => info!("loaded changed certificate for `{}`", domain),
+                // Whatever was served before still is.
+                Err(e) => warn!("not reloading {}", e),
+            }
+        }
+        for dir in self.seen.keys().filter(|dir| !current.contains_key(*dir)) {
+            let domain = domain_name(dir);
+            let elsewhere = current.keys().any(|other| domain_name(other) == domain);
+            if !elsewhere && !pinned.contains(&normalize(domain)) && resolver.remove(domain) {
+                info!(
+                    "removed certificate for `{}`, its directory is gone",
+                    domain
+                );
+            }
+        }
+        self.seen = current;
+    }
+}
+
+fn stamps(config: &Config) -> Stamps {
+    let mut stamps = Stamps::new();
+    for dir in domain_dirs(config) {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            if !path.is_dir() || path.file_name().and_then(|name| name.to_str()).is_none() {
+                continue;
+            }
+            let stamp = CERT_FILES
+                .iter()
+                .chain(&[KEY_FILE])
+                .map(|file| {
+                    fs::metadata(path.join(file))
+                        .and_then(|m| m.modified())
+                        .ok()
+                })
+                .collect();
+            stamps.insert(path, stamp);
+        }
+    }
+    stamps
+}
+
+/// The name of a domain directory, which `stamps` made sure is UTF-8.
+fn domain_name(dir: &Path) -> &str {
+    dir.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+/// The directories holding a `<domain>/` directory per certificate. The
+/// configured certificate directory has to exist, the default one doesn't.
+fn domain_dirs(config: &Config) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    match &config.certificate_dir {
+        Some(dir) => dirs.push(dir.clone()),
+        None => dirs.extend(default_dir().filter(|dir| dir.is_dir())),
+    }
+    if let Some(acme) = config.acme.as_ref().filter(|acme| acme.storage.is_dir()) {
+        dirs.push(acme.storage.clone());
+    }
+    dirs
+}
+
+fn load_domain_dir(domain: &str, dir: &Path) -> Result<CertifiedKey, Error> {
+    let cert = CERT_FILES
+        .iter()
+        .map(|file| dir.join(file))
+        .find(|file| file.exists())
+        .ok_or_else(|| Error::Certificate {
+            domain: domain.to_string(),
+            reason: format!("`{}` has no {}", dir.display(), CERT_FILES.join(" or ")),
+        })?;
+    tls::load_certified_key(domain, &cert, &dir.join(KEY_FILE))
+}
+
+/// `$XDG_CONFIG_HOME/proxy`, or `~/.config/proxy` if that isn't set.
+fn default_dir() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("proxy"))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Certificates, Resolver};
+    use crate::tls::load_certified_key;
+    use rustls::sign::CertifiedKey;
+    use rustls::Certificate;
+    use std::path::Path;
+
+    fn key(name: &str) -> CertifiedKey {
+        let key = load_certified_key(name, Path::new("cert.pem"), Path::new("privkey.pem"));
+        // Tag the chain so the tests can tell which one was served.
+        CertifiedKey::new(vec![Certificate(name.into())], key.unwrap().key)
+    }
+
+    #[test]
+    fn matches_exact_names_before_wildcards() {
+        let mut certificates = Certificates::default();
+        certificates
+            .insert("*.example.com", key("wildcard"))
+            .unwrap();
+        certificates.insert("www.Example.com.", key("www")).unwrap();
+        assert!(certificates.insert("exa mple.com", key("bad")).is_err());
+
+        let served = |name| {
+            certificates
+                .get(name)
+                .map(|key| String::from_utf8(key.cert[0].0.clone()).unwrap())
+        };
+        assert_eq!(served("www.example.com"), Some("www".to_string()));
+        assert_eq!(served("api.example.com"), Some("wildcard".to_string()));
+        assert_eq!(served("example.com"), None);
+        assert_eq!(served("a.b.example.com"), None);
+
+        let resolver = Resolver::new(certificates);
+        assert!(resolver.remove("*.example.com"));
+        assert!(!resolver.remove("*.example.com"));
+        assert!(resolver.get("api.example.com").is_none());
+        resolver.insert("api.example.com", key("api")).unwrap();
+        assert!(resolver.get("API.example.com").is_some());
+    }
+
+    #[test]
+    fn falls_back_to_the_default_certificate() {
+        let mut certificates = Certificates::default();
+        certificates.insert("example.com", key("example")).unwrap();
+        assert!(certificates.resolve(None).is_none());
+        assert!(certificates.resolve(Some("other.com")).is_none());
+
+        certificates.default = Some(key("default"));
+        let served = |certificates: &Certificates, name| {
+            certificates
+                .resolve(name)
+                .map(|key| String::from_utf8(key.cert[0].0.clone()).unwrap())
+        };
+        assert_eq!(served(&certificates, None), Some("default".to_string()));
+        assert_eq!(
+            served(&certificates, Some("other.com")),
+            Some("default".to_string())
+        );
+        assert_eq!(
+            served(&certificates, Some("example.com")),
+            Some("example".to_string())
+        );
+
+        certificates.reject_unknown_names = true;
+        assert_eq!(served(&certificates, None), Some("default".to_string()));
+        assert_eq!(served(&certificates, Some("other.com")), None);
+    }
+
+    #[test]
+    fn applies_changes_to_the_next_handshake() {
+        let resolver = Resolver::new(Certificates {
+            default: Some(key("default")),
+            ..Certificates::default()
+        });
+        let served = |name| {
+            resolver
+                .resolve_name(Some(name))
+                .map(|key| String::from_utf8(key.cert[0].0.clone()).unwrap())
+        };
+        assert_eq!(served("example.com"), Some("default".to_string()));
+
+        resolver.insert("example.com", key("added")).unwrap();
+        assert_eq!(served("example.com"), Some("added".to_string()));
+        resolver.insert("example.com", key("replaced")).unwrap();
+        assert_eq!(served("example.com"), Some("replaced".to_string()));
+        assert!(resolver.remove("example.com"));
+        assert_eq!(served("example.com"), Some("default".to_string()));
+
+        resolver.replace(Certificates::default());
+        assert_eq!(served("example.com"), None);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config::{ClientAuthConfig, ClientAuthMode, HostConfig};
+use crate::errors::Error;
+use crate::x509;
+use ring::digest::{digest, SHA256};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+    ClientCertVerified, ClientCertVerifier, DistinguishedNames, RootCertStore, TLSError,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Headers telling upstreams about the client's verified certificate. Any
+/// the client sent itself are removed.
+pub const X_CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+pub const X_CLIENT_CERT_SAN: &str = "x-client-cert-san";
+pub const X_CLIENT_CERT_FINGERPRINT: &str = "x-client-cert-fingerprint";
+
+/// The verified certificate a client authenticated with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCert {
+    /// In the RFC 4514 form, e.g. `CN=client,O=Example`.
+    pub subject: String,
+    /// e.g. `DNS:client.example.com` or `email:client@example.com`.
+    pub sans: Vec<String>,
+    /// SHA-256 of the DER certificate, in lowercase hex.
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    pub fn new(der: &[u8]) -> ClientCert {
+        let fingerprint = digest(&SHA256, der);
+        ClientCert {
+            subject: x509::subject(der).unwrap_or_default(),
+            sans: x509::subject_alt_names(der),
+            fingerprint: fingerprint
+                .as_ref()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        }
+    }
+}
+
+/// The client authentication of a TLS listener: its own, and that of every
+/// host with one, by name.
+#[derive(Default)]
+pub struct ClientAuth {
+    pub default: Option<Arc<dyn ClientCertVerifier>>,
+    pub by_name: HashMap<String, Arc<dyn ClientCertVerifier>>,
+}
+
+impl ClientAuth {
+    pub fn new(
+        listener: Option<&ClientAuthConfig>,
+        hosts: &[HostConfig],
+    ) -> Result<ClientAuth, Error> {
+        let mut client_auth = ClientAuth {
+            default: listener.map(verifier).transpose()?,
+            by_name: HashMap::new(),
+        };
+        for host in hosts {
+            if let Some(config) = &host.client_auth {
+                let verifier = verifier(config)?;
+                for name in &host.names {
+                    client_auth
+                        .by_name
+                        .insert(name.to_ascii_lowercase(), verifier.clone());
+                }
+            }
+        }
+        Ok(client_auth)
+    }
+}
+
+/// Checks client certificates against a CA bundle, then against the
+/// certificates revoked by the CRLs.
+struct Verifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    /// Serial numbers of revoked certificates, by the DER name of their
+    /// issuer.
+    revoked: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+}
+
+impl ClientCertVerifier for Verifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&webpki::DNSName>) -> Option<bool> {
+        self.inner.client_auth_mandatory(sni)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs, sni)?;
+        for cert in presented_certs {
+            let (issuer, serial) = x509::issuer_and_serial(&cert.0)
+                .ok_or_else(|| TLSError::General("client certificate doesn't parse".into()))?;
+            if self
+                .revoked
+                .get(issuer)
+                .is_some_and(|serials| serials.contains(serial))
+            {
+                return Err(TLSError::General("client certificate is revoked".into()));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+fn verifier(config: &ClientAuthConfig) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let mut roots = RootCertStore::empty();
+    let mut cas = Vec::new();
+    for (label, der) in read_pem(&config.ca)? {
+        if label == "CERTIFICATE" {
+            roots.add(&Certificate(der.clone())).map_err(|e| {
+                Error::Config(format!(
+                    "`{}` has an invalid CA certificate: {}",
+                    config.ca.display(),
+                    e
+                ))
+            })?;
+            cas.push(der);
+        }
+    }
+    if roots.is_empty() {
+        return Err(Error::Config(format!(
+            "`{}` contains no CA certificates",
+            config.ca.display()
+        )));
+    }
+
+    let mut revoked: HashMap<Vec<u8>, HashSet<Vec<u8>>> = HashMap::new();
+    for path in &config.crls {
+        let data = fs::read(path)
+            .map_err(|e| Error::Config(format!("could not read `{}`: {}", path.display(), e)))?;
+        // PEM if it reads as text, DER otherwise.
+        let crls = match std::str::from_utf8(&data) {
+            Ok(_) => read_pem(path)?
+                .into_iter()
+                .filter(|(label, _)| label == "X509 CRL")
+                .map(|(_, der)| der)
+                .collect(),
+            Err(_) => vec![data],
+        };
+        if crls.is_empty() {
+            return Err(Error::Config(format!(
+                "`{}` contains no CRLs",
+                path.display()
+            )));
+        }
+        for crl in &crls {
+            let (issuer, serials) = x509::crl_revocations(crl, &cas, SystemTime::now())
+                .map_err(|e| Error::Config(format!("`{}` {}", path.display(), e)))?;
+            revoked.entry(issuer).or_default().extend(serials);
+        }
+    }
+
+    let inner = match config.mode {
+        ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots),
+        ClientAuthMode::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+    };
+    Ok(Arc::new(Verifier { inner, revoked }))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let pem = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("could not read `{}`: {}", path.display(), e)))?;
+    x509::pem_blocks(&pem).map_err(|e| {
+        Error::Config(format!(
+            "`{}` is not a valid PEM file: {}",
+            path.display(),
+            e
+        ))
+    })
+}
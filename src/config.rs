@@ -0,0 +1,1444 @@
+use crate::client_auth::ClientCert;
+use crate::connector;
+use crate::errors::Error;
+use hyper::Uri;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Top level proxy configuration, usually read from `proxy.toml`.
+///
+/// ```toml
+/// [defaults]
+/// max_buffered_body = 1048576
+/// redirects = "off"
+///
+/// [[listeners]]
+/// address = "127.0.0.1:1337"
+/// handshake_timeout = "10s"
+///
+/// [[certificates]]
+/// domain = "localhost"
+/// cert = "cert.pem"
+/// key = "privkey.pem"
+///
+/// [upstreams.app]
+/// url = "http://localhost:3000"
+///
+/// [[hosts]]
+/// names = ["localhost"]
+/// upstream = "app"
+/// default = true
+///
+/// [[hosts.routes]]
+/// path_prefix = "/api"
+/// upstream = "app"
+/// strip_prefix = true
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: Defaults,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub certificates: Vec<CertificateConfig>,
+    /// Holds a `<domain>/` directory with `fullchain.pem` (or `cert.pem`)
+    /// and `privkey.pem` per certificate. Defaults to
+    /// `$XDG_CONFIG_HOME/proxy`, which is skipped if it doesn't exist.
+    pub certificate_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>,
+    #[serde(default)]
+    pub retry_budget: RetryBudgetConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub acme: Option<AcmeConfig>,
+    /// How long requests in flight get to finish after SIGTERM or SIGINT.
+    #[serde(default = "default_drain_timeout", with = "humantime_serde")]
+    pub drain_timeout: Duration,
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Where the proxy serves its counters in the Prometheus text format, at
+/// `/metrics` over plain HTTP.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub address: SocketAddr,
+}
+
+/// Certificates obtained and renewed from an ACME CA such as Let's
+/// Encrypt, kept in `storage` in the layout of `certificate_dir`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Names to get certificates for. Defaults to every host name that has
+    /// no `[[certificates]]` entry, leaving out wildcards, which neither
+    /// challenge can validate.
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    /// Holds the account key and a `<domain>/` directory per certificate.
+    pub storage: PathBuf,
+    /// CA certificates the ACME server is verified with instead of the
+    /// system roots, e.g. those of a test server.
+    pub ca: Option<PathBuf>,
+    #[serde(default = "default_renew_before", with = "humantime_serde")]
+    pub renew_before: Duration,
+}
+
+/// How the ACME server checks that we control a domain: by fetching a
+/// token over plain HTTP on port 80, or with a TLS handshake on port 443.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl AcmeChallenge {
+    pub fn name(self) -> &'static str {
+        match self {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+}
+
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_renew_before() -> Duration {
+    Duration::from_secs(30 * 24 * 60 * 60)
+}
+
+/// Settings every route uses unless it overrides them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Defaults {
+    /// Largest request body, in bytes, held in memory so it can be sent
+    /// again when an upstream redirects with 307 or 308. Larger ones get a
+    /// 413 instead of following the redirect.
+    pub max_buffered_body: usize,
+    /// Which upstream redirects the proxy follows itself.
+    pub redirects: RedirectsConfig,
+    /// How many redirects are followed before giving up with a 502.
+    pub max_redirects: usize,
+    /// How requests are spread over the servers of an upstream.
+    pub balance: BalanceConfig,
+    /// When failed requests are sent again.
+    pub retries: RetryConfig,
+    /// Pages sent for the proxy's own errors.
+    pub error_pages: ErrorPagesConfig,
+    /// How long each stage of proxying a request may take.
+    pub timeouts: TimeoutsConfig,
+}
+
+impl Default for Defaults {
+    fn default() -> Defaults {
+        Defaults {
+            max_buffered_body: 1024 * 1024,
+            redirects: RedirectsConfig::Mode(RedirectMode::Off),
+            max_redirects: 10,
+            balance: BalanceConfig::Strategy(BalanceStrategy::RoundRobin),
+            retries: RetryConfig::default(),
+            error_pages: ErrorPagesConfig::default(),
+            timeouts: TimeoutsConfig::default(),
+        }
+    }
+}
+
+/// How long the stages of a request may take before the proxy gives up.
+/// `connect` covers connecting to the upstream, TLS included, and
+/// `first_byte` waiting for its response headers once the request body was
+/// sent, both answered with a 504. `client_body` is the longest the client may pause while sending the
+/// request body, answered with a 408. `total` caps the whole exchange up to
+/// the response headers and is off unless set.
+///
+/// A route's `timeouts` table replaces the default one as a whole.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TimeoutsConfig {
+    #[serde(with = "humantime_serde")]
+    pub connect: Duration,
+    #[serde(with = "humantime_serde")]
+    pub first_byte: Duration,
+    #[serde(with = "humantime_serde")]
+    pub client_body: Duration,
+    #[serde(with = "humantime_serde")]
+    pub total: Option<Duration>,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> TimeoutsConfig {
+        TimeoutsConfig {
+            connect: Duration::from_secs(10),
+            first_byte: Duration::from_secs(60),
+            client_body: Duration::from_secs(60),
+            total: None,
+        }
+    }
+}
+
+/// Templates for the pages sent when the proxy can't get a response from
+/// the upstream, picked by the client's `Accept` header. Both default to a
+/// built-in page. Templates may use `{{status}}`, `{{reason}}`, `{{error}}`
+/// (the `X-Proxy-Error` code), `{{request_id}}` and `{{upstream}}`.
+///
+/// With `intercept_upstream_errors`, 5xx responses from the upstream are
+/// replaced by these pages too. A route's `error_pages` table replaces the
+/// default one as a whole.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ErrorPagesConfig {
+    pub html: Option<PathBuf>,
+    pub json: Option<PathBuf>,
+    pub intercept_upstream_errors: bool,
+}
+
+/// Retries a request up to `attempts` more times, each on a server it
+/// hasn't been sent to yet if the upstream has one. `retry_on` lists the
+/// failures to retry, `"connect_failure"` and `"reset"` (the connection
+/// broke before a response), and `retry_on_status` the upstream statuses.
+///
+/// Only idempotent methods are retried unless `non_idempotent` is set, and
+/// only requests whose body is small enough to be buffered. Retries wait a
+/// random time up to `backoff`, doubled for every retry up to `max_backoff`.
+/// A route's `retries` table replaces the default one as a whole.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RetryConfig {
+    pub attempts: u32,
+    pub retry_on: Vec<RetryOn>,
+    pub retry_on_status: Vec<u16>,
+    pub non_idempotent: bool,
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            attempts: 0,
+            retry_on: vec![RetryOn::ConnectFailure],
+            retry_on_status: Vec::new(),
+            non_idempotent: false,
+            backoff: Duration::from_millis(25),
+            max_backoff: Duration::from_millis(250),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    ConnectFailure,
+    Reset,
+}
+
+/// Caps retries across all routes: every request earns `ratio` of a retry,
+/// and up to `burst` retries can be saved up.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RetryBudgetConfig {
+    pub ratio: f64,
+    pub burst: u32,
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> RetryBudgetConfig {
+        RetryBudgetConfig {
+            ratio: 0.2,
+            burst: 10,
+        }
+    }
+}
+
+/// Either `"off"`, `"same_host"`, `"any"`, or a table with a list of
+/// `allow_hosts`, which may start with `*.` to allow every subdomain.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum RedirectsConfig {
+    Mode(RedirectMode),
+    Hosts { allow_hosts: Vec<String> },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectMode {
+    Off,
+    SameHost,
+    Any,
+}
+
+/// Either `"round_robin"`, `"weighted_round_robin"`, `"least_requests"`,
+/// `"random_two_choices"`, or a table hashing requests onto servers by
+/// `hash = "client_ip"`, or by the header or cookie `hash = "header"` and
+/// `hash = "cookie"` with its `name`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BalanceConfig {
+    Strategy(BalanceStrategy),
+    Hash { hash: HashKey, name: Option<String> },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastRequests,
+    RandomTwoChoices,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    ClientIp,
+    Header,
+    Cookie,
+}
+
+/// Where a listener binds: `"127.0.0.1:443"` or `"unix:/run/proxy.sock"`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BindAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for BindAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<BindAddress, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(BindAddress::Unix(PathBuf::from(path))),
+            None => s.parse().map(BindAddress::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddress::Tcp(addr) => write!(f, "{}", addr),
+            BindAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BindAddress, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// An address the proxy accepts connections on.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: BindAddress,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Answer plain HTTP requests with this redirect status (301 or 308) to
+    /// the `https://` url instead of proxying them.
+    pub redirect_to_https: Option<u16>,
+    /// Port to put in the `https://` url, if the TLS listener isn't on 443.
+    pub https_port: Option<u16>,
+    /// How many TLS handshakes may be in progress at once.
+    #[serde(default = "default_max_handshakes")]
+    pub max_handshakes: usize,
+    /// How long a client gets to finish its TLS handshake, e.g. `"10s"`.
+    #[serde(default = "default_handshake_timeout", with = "humantime_serde")]
+    pub handshake_timeout: Duration,
+    /// How long a client gets to send a request's headers once it started,
+    /// answered with a 408 over HTTP/1.
+    #[serde(default = "default_header_read_timeout", with = "humantime_serde")]
+    pub header_read_timeout: Duration,
+    /// How long a connection is kept open without requests.
+    #[serde(default = "default_idle_timeout", with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+/// Client certificate authentication of TLS handshakes: certificates must
+/// chain to one of the CAs in the `ca` bundle and not be revoked by any of
+/// the `crls`, PEM or DER files. Each CRL must be signed by one of the CAs
+/// and not be past its next update.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    pub ca: PathBuf,
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    #[serde(default)]
+    pub crls: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Handshakes without a certificate fail.
+    #[default]
+    Required,
+    /// Clients may go without a certificate, but one they send must be
+    /// valid.
+    Optional,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    #[default]
+    Https,
+    Http,
+}
+
+fn default_max_handshakes() -> usize {
+    1024
+}
+
+fn default_handshake_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_header_read_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// How handshakes are answered that ask for no name, or for a name without
+/// a certificate.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TlsConfig {
+    pub default_certificate: Option<DefaultCertificateConfig>,
+    /// Generate a self-signed default certificate on startup, for
+    /// development.
+    pub self_signed_default: bool,
+    /// Fail handshakes for names without a certificate with an
+    /// `unrecognized_name` alert, rather than serving the default
+    /// certificate. Handshakes without a name still get it.
+    pub reject_unknown_names: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultCertificateConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A certificate/key pair served for `domain` via SNI.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    pub domain: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A named upstream service requests can be forwarded to. Either a single
+/// `url`, or a pool of `servers` with optional weights. Urls use `http://`,
+/// `https://`, or `unix:/run/app.sock` for a Unix socket.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    #[serde(default, deserialize_with = "deserialize_optional_upstream_url")]
+    pub url: Option<Uri>,
+    #[serde(default)]
+    pub servers: Vec<ServerConfig>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    pub tls: Option<UpstreamTlsConfig>,
+}
+
+/// How the `https://` servers of an upstream are connected to, e.g. ones
+/// with certificates from a private CA. Redirects to other servers use the
+/// defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle of the CAs to trust instead of the system's.
+    pub ca: Option<PathBuf>,
+    /// Client certificate chain and key to authenticate with.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Sent as SNI and checked against the certificate instead of the
+    /// url's host.
+    pub server_name: Option<String>,
+    pub min_version: TlsVersion,
+    /// Accept any certificate. Only ever meant for development.
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// One server of an upstream pool.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(deserialize_with = "deserialize_upstream_url")]
+    pub url: Uri,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Probes every server of an upstream on an `interval`. A server is taken
+/// out of rotation after `fall` failed probes in a row, and put back after
+/// `rise` successful ones. Servers start out healthy.
+///
+/// HTTP probes `GET` the `path` and expect `expect_status` (any 2xx if
+/// unset) and, if set, a body containing `expect_body`. TCP probes only
+/// check that a connection can be opened.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default)]
+    pub protocol: HealthCheckProtocol,
+    pub path: Option<String>,
+    pub expect_status: Option<u16>,
+    pub expect_body: Option<String>,
+    #[serde(default = "default_health_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_health_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(default = "default_rise")]
+    pub rise: u32,
+    #[serde(default = "default_fall")]
+    pub fall: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckProtocol {
+    #[default]
+    Http,
+    Tcp,
+}
+
+/// Takes a server out of rotation after `consecutive_failures` requests in
+/// a row failed to connect or got a 5xx. It stays out for
+/// `base_ejection_time`, doubled for every ejection in a row up to
+/// `max_ejection_time`. No more than `max_ejected_percent` of the servers
+/// are ejected at once.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default = "default_base_ejection_time", with = "humantime_serde")]
+    pub base_ejection_time: Duration,
+    #[serde(default = "default_max_ejection_time", with = "humantime_serde")]
+    pub max_ejection_time: Duration,
+    #[serde(default = "default_max_ejected_percent")]
+    pub max_ejected_percent: u8,
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_base_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_max_ejection_time() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_max_ejected_percent() -> u8 {
+    50
+}
+
+fn default_health_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_health_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_rise() -> u32 {
+    2
+}
+
+fn default_fall() -> u32 {
+    3
+}
+
+impl UpstreamConfig {
+    /// The servers of this upstream and their weights.
+    pub fn servers(&self) -> impl Iterator<Item = (&Uri, u32)> {
+        self.url.iter().map(|url| (url, default_weight())).chain(
+            self.servers
+                .iter()
+                .map(|server| (&server.url, server.weight)),
+        )
+    }
+}
+
+/// A virtual host: requests whose SNI name or `Host` header is one of
+/// `names` are matched against `routes`, and sent to `upstream` if none of
+/// them match. The `default` host serves every name not claimed by another
+/// host.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    #[serde(default)]
+    pub names: Vec<String>,
+    pub upstream: Option<String>,
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Replaces the listener's client authentication for handshakes asking
+    /// for one of `names`. Requests are only routed to the host over such a
+    /// handshake.
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+/// A path based route inside a virtual host. Exactly one of `path`,
+/// `path_prefix` and `path_regex` must be set.
+///
+/// Exact paths are tried first, then prefixes from longest to shortest, then
+/// regexes in the order they are declared. `rewrite` replaces the matched
+/// part of the path (regexes may refer to capture groups as `$1`), while
+/// `strip_prefix` removes a matched prefix before forwarding. The remaining
+/// fields override the ones in [`Defaults`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub path: Option<String>,
+    pub path_prefix: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub path_regex: Option<Regex>,
+    pub upstream: String,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    pub rewrite: Option<String>,
+    pub max_buffered_body: Option<usize>,
+    pub redirects: Option<RedirectsConfig>,
+    pub max_redirects: Option<usize>,
+    pub balance: Option<BalanceConfig>,
+    pub retries: Option<RetryConfig>,
+    pub error_pages: Option<ErrorPagesConfig>,
+    pub timeouts: Option<TimeoutsConfig>,
+    pub client_cert: Option<ClientCertRule>,
+}
+
+/// The client certificates a route accepts, answering requests without one
+/// of them with a 403. A certificate is accepted if its subject, one of its
+/// subject alternative names or its SHA-256 fingerprint is listed, in the
+/// form they are passed upstream in, e.g. `"CN=admin,O=Example"`,
+/// `"email:admin@example.com"`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ClientCertRule {
+    pub subjects: Vec<String>,
+    pub sans: Vec<String>,
+    pub fingerprints: Vec<String>,
+}
+
+impl ClientCertRule {
+    pub fn allows(&self, cert: Option<&ClientCert>) -> bool {
+        let cert = match cert {
+            Some(cert) => cert,
+            None => return false,
+        };
+        self.subjects.contains(&cert.subject)
+            || cert.sans.iter().any(|san| self.sans.contains(san))
+            || self.fingerprints.iter().any(|fingerprint| {
+                fingerprint
+                    .replace(':', "")
+                    .eq_ignore_ascii_case(&cert.fingerprint)
+            })
+    }
+}
+
+fn deserialize_upstream_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uri, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    match s.strip_prefix("unix:") {
+        Some(path) => connector::unix_base(Path::new(path)).parse(),
+        _ => s.parse(),
+    }
+    .map_err(de::Error::custom)
+}
+
+fn deserialize_optional_upstream_url<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Uri>, D::Error> {
+    deserialize_upstream_url(deserializer).map(Some)
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Regex::new(&s).map(Some).map_err(de::Error::custom)
+}
+
+impl Config {
+    /// Read, parse and validate the configuration file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("could not read `{}`: {}", path.display(), e)))?;
+
+        contents.parse()
+    }
+
+    /// Check the parts of the config that can't be expressed in its types.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.listeners.is_empty() {
+            return Err(Error::Config("at least one listener is required".into()));
+        }
+
+        let mut addresses = HashSet::new();
+        for listener in &self.listeners {
+            if listener.header_read_timeout.is_zero() || listener.idle_timeout.is_zero() {
+                return Err(Error::Config(format!(
+                    "listener `{}` needs non-zero timeouts",
+                    listener.address
+                )));
+            }
+            if listener.max_handshakes == 0 {
+                return Err(Error::Config(format!(
+                    "listener `{}` must allow at least one handshake",
+                    listener.address
+                )));
+            }
+            if let Some(status) = listener.redirect_to_https {
+                if listener.protocol != Protocol::Http {
+                    return Err(Error::Config(format!(
+                        "listener `{}` can only use `redirect_to_https` with `protocol = \"http\"`",
+                        listener.address
+                    )));
+                }
+                if status != 301 && status != 308 {
+                    return Err(Error::Config(format!(
+                        "listener `{}` must use 301 or 308 for `redirect_to_https`",
+                        listener.address
+                    )));
+                }
+            } else if listener.https_port.is_some() {
+                return Err(Error::Config(format!(
+                    "listener `{}` can only use `https_port` with `redirect_to_https`",
+                    listener.address
+                )));
+            }
+            if matches!(listener.address, BindAddress::Unix(_))
+                && listener.protocol != Protocol::Http
+            {
+                return Err(Error::Config(format!(
+                    "listener `{}` must use `protocol = \"http\"` on a Unix socket",
+                    listener.address
+                )));
+            }
+            if listener.client_auth.is_some() && listener.protocol == Protocol::Http {
+                return Err(Error::Config(format!(
+                    "listener `{}` can only use `client_auth` with TLS",
+                    listener.address
+                )));
+            }
+            if !addresses.insert(listener.address.clone()) {
+                return Err(Error::Config(format!(
+                    "listener address `{}` is declared more than once",
+                    listener.address
+                )));
+            }
+        }
+
+        if self.tls.default_certificate.is_some() && self.tls.self_signed_default {
+            return Err(Error::Config(
+                "`tls` can't set both `default_certificate` and `self_signed_default`".into(),
+            ));
+        }
+
+        let mut domains = HashSet::new();
+        for cert in &self.certificates {
+            if !domains.insert(cert.domain.to_ascii_lowercase()) {
+                return Err(Error::Config(format!(
+                    "certificate for `{}` is declared more than once",
+                    cert.domain
+                )));
+            }
+        }
+
+        check_balance(&self.defaults.balance)
+            .map_err(|reason| Error::Config(format!("`defaults.balance` {}", reason)))?;
+        check_retries(&self.defaults.retries)
+            .map_err(|reason| Error::Config(format!("`defaults.retries` {}", reason)))?;
+        check_timeouts(&self.defaults.timeouts)
+            .map_err(|reason| Error::Config(format!("`defaults.timeouts` {}", reason)))?;
+        check_redirects(&self.defaults.redirects)
+            .map_err(|reason| Error::Config(format!("`defaults.redirects` {}", reason)))?;
+        if !(0.0..=1.0).contains(&self.retry_budget.ratio) {
+            return Err(Error::Config(
+                "`retry_budget.ratio` must be between 0 and 1".into(),
+            ));
+        }
+
+        for (name, upstream) in &self.upstreams {
+            if upstream.url.is_some() != upstream.servers.is_empty() {
+                return Err(Error::Config(format!(
+                    "upstream `{}` must set exactly one of `url` and `servers`",
+                    name
+                )));
+            }
+            for (url, weight) in upstream.servers() {
+                check_upstream_url(name, url)?;
+                if weight == 0 {
+                    return Err(Error::Config(format!(
+                        "upstream `{}` server `{}` must have a weight of at least 1",
+                        name, url
+                    )));
+                }
+            }
+            if let Some(check) = &upstream.health_check {
+                check_health_check(check).map_err(|reason| {
+                    Error::Config(format!("upstream `{}` health check {}", name, reason))
+                })?;
+            }
+            if let Some(outliers) = &upstream.outlier_detection {
+                check_outlier_detection(outliers).map_err(|reason| {
+                    Error::Config(format!("upstream `{}` outlier detection {}", name, reason))
+                })?;
+            }
+            if let Some(tls) = &upstream.tls {
+                if tls.cert.is_some() != tls.key.is_some() {
+                    return Err(Error::Config(format!(
+                        "upstream `{}` tls must set both `cert` and `key`, or neither",
+                        name
+                    )));
+                }
+                if tls.insecure_skip_verify && tls.ca.is_some() {
+                    return Err(Error::Config(format!(
+                        "upstream `{}` tls can't set both `ca` and `insecure_skip_verify`",
+                        name
+                    )));
+                }
+            }
+        }
+
+        if self.hosts.is_empty() {
+            return Err(Error::Config("at least one host is required".into()));
+        }
+
+        let mut names = HashSet::new();
+        let mut has_default = false;
+        for host in &self.hosts {
+            let label = host.names.first().map_or("default", String::as_str);
+            if host.names.is_empty() && !host.default {
+                return Err(Error::Config(
+                    "a host without names must be marked `default = true`".into(),
+                ));
+            }
+            if host.default && std::mem::replace(&mut has_default, true) {
+                return Err(Error::Config(
+                    "only one host may be marked `default = true`".into(),
+                ));
+            }
+            if host.upstream.is_none() && host.routes.is_empty() {
+                return Err(Error::Config(format!(
+                    "host `{}` needs an upstream or at least one route",
+                    label
+                )));
+            }
+            if host.client_auth.is_some() && host.names.is_empty() {
+                return Err(Error::Config(
+                    "a host without names can't use `client_auth`".into(),
+                ));
+            }
+            if let Some(upstream) = &host.upstream {
+                self.check_upstream(label, upstream)?;
+            }
+            for route in &host.routes {
+                self.check_route(label, route)?;
+            }
+            for name in &host.names {
+                if !names.insert(name.to_ascii_lowercase()) {
+                    return Err(Error::Config(format!(
+                        "host name `{}` is declared more than once",
+                        name
+                    )));
+                }
+            }
+        }
+
+        if let Some(acme) = &self.acme {
+            self.check_acme(acme)?;
+        }
+
+        Ok(())
+    }
+
+    /// The hosts with their own client authentication, which only changes
+    /// on restart.
+    pub fn client_auth_hosts(&self) -> Vec<(&[String], &ClientAuthConfig)> {
+        self.hosts
+            .iter()
+            .filter_map(|host| Some((host.names.as_slice(), host.client_auth.as_ref()?)))
+            .collect()
+    }
+
+    /// The names ACME certificates are obtained for.
+    pub fn acme_domains(&self) -> Vec<String> {
+        let acme = match &self.acme {
+            Some(acme) => acme,
+            None => return Vec::new(),
+        };
+        if !acme.domains.is_empty() {
+            return acme.domains.clone();
+        }
+        self.hosts
+            .iter()
+            .flat_map(|host| &host.names)
+            .filter(|name| !name.contains('*'))
+            .filter(|name| {
+                !self
+                    .certificates
+                    .iter()
+                    .any(|cert| cert.domain.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn check_acme(&self, acme: &AcmeConfig) -> Result<(), Error> {
+        if let Some(domain) = acme.domains.iter().find(|domain| domain.contains('*')) {
+            return Err(Error::Config(format!(
+                "`acme` can't get a certificate for wildcard `{}`",
+                domain
+            )));
+        }
+        if self.acme_domains().is_empty() {
+            return Err(Error::Config("`acme` has no domains".into()));
+        }
+        // The challenge is answered by a listener of the right protocol.
+        let protocol = match acme.challenge {
+            AcmeChallenge::Http01 => Protocol::Http,
+            AcmeChallenge::TlsAlpn01 => Protocol::Https,
+        };
+        let answered = self.listeners.iter().any(|listener| {
+            matches!(listener.address, BindAddress::Tcp(_)) && listener.protocol == protocol
+        });
+        if !answered {
+            return Err(Error::Config(format!(
+                "`acme` {} challenges need a TCP listener with `protocol = \"{}\"`",
+                acme.challenge.name(),
+                if protocol == Protocol::Http {
+                    "http"
+                } else {
+                    "https"
+                }
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_upstream(&self, host: &str, upstream: &str) -> Result<(), Error> {
+        if self.upstreams.contains_key(upstream) {
+            Ok(())
+        } else {
+            Err(Error::Config(format!(
+                "host `{}` references unknown upstream `{}`",
+                host, upstream
+            )))
+        }
+    }
+
+    fn check_route(&self, host: &str, route: &RouteConfig) -> Result<(), Error> {
+        let invalid = |reason: &str| {
+            Err(Error::Config(format!(
+                "route `{}` of host `{}` {}",
+                route.describe(),
+                host,
+                reason
+            )))
+        };
+
+        let matchers = [
+            route.path.is_some(),
+            route.path_prefix.is_some(),
+            route.path_regex.is_some(),
+        ];
+        if matchers.iter().filter(|set| **set).count() != 1 {
+            return invalid("must set exactly one of `path`, `path_prefix` and `path_regex`");
+        }
+        if let Some(path) = route.path.as_ref().or(route.path_prefix.as_ref()) {
+            if !path.starts_with('/') {
+                return invalid("must start with `/`");
+            }
+        }
+        if route.strip_prefix && route.path_prefix.is_none() {
+            return invalid("can only use `strip_prefix` with `path_prefix`");
+        }
+        if route.strip_prefix && route.rewrite.is_some() {
+            return invalid("can't set both `strip_prefix` and `rewrite`");
+        }
+        // Regex rewrites are checked by their template, so a capture can't
+        // turn the path into something that isn't one.
+        match &route.rewrite {
+            Some(rewrite) if !rewrite.starts_with('/') => {
+                return invalid("must `rewrite` to a path starting with `/`");
+            }
+            _ => {}
+        }
+        if let Some(balance) = &route.balance {
+            if let Err(reason) = check_balance(balance) {
+                return invalid(&format!("`balance` {}", reason));
+            }
+        }
+        if let Some(retries) = &route.retries {
+            if let Err(reason) = check_retries(retries) {
+                return invalid(&format!("`retries` {}", reason));
+            }
+        }
+        if let Some(timeouts) = &route.timeouts {
+            if let Err(reason) = check_timeouts(timeouts) {
+                return invalid(&format!("`timeouts` {}", reason));
+            }
+        }
+        if let Some(redirects) = &route.redirects {
+            if let Err(reason) = check_redirects(redirects) {
+                return invalid(&format!("`redirects` {}", reason));
+            }
+        }
+        self.check_upstream(host, &route.upstream)
+    }
+}
+
+fn check_upstream_url(name: &str, url: &Uri) -> Result<(), Error> {
+    if !matches!(
+        url.scheme_str(),
+        Some("http") | Some("https") | Some("unix")
+    ) {
+        return Err(Error::Config(format!(
+            "upstream `{}` url `{}` must use the http, https or unix scheme",
+            name, url
+        )));
+    }
+    if url.authority().is_none() {
+        return Err(Error::Config(format!(
+            "upstream `{}` url `{}` is missing a host",
+            name, url
+        )));
+    }
+    if !matches!(url.path(), "" | "/") || url.query().is_some() {
+        return Err(Error::Config(format!(
+            "upstream `{}` url `{}` must not contain a path or query",
+            name, url
+        )));
+    }
+    Ok(())
+}
+
+fn check_health_check(check: &HealthCheckConfig) -> Result<(), String> {
+    if check.interval.is_zero() || check.timeout.is_zero() {
+        return Err("needs a non-zero `interval` and `timeout`".into());
+    }
+    if check.rise == 0 || check.fall == 0 {
+        return Err("needs `rise` and `fall` of at least 1".into());
+    }
+    match check.protocol {
+        HealthCheckProtocol::Http => {
+            if check
+                .path
+                .as_deref()
+                .is_some_and(|path| !path.starts_with('/'))
+            {
+                return Err("`path` must start with `/`".into());
+            }
+            if check
+                .expect_status
+                .is_some_and(|status| !(100..600).contains(&status))
+            {
+                return Err("`expect_status` must be a valid status code".into());
+            }
+        }
+        HealthCheckProtocol::Tcp => {
+            if check.path.is_some() || check.expect_status.is_some() || check.expect_body.is_some()
+            {
+                return Err("can't set `path`, `expect_status` or `expect_body` over tcp".into());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_outlier_detection(outliers: &OutlierDetectionConfig) -> Result<(), String> {
+    if outliers.consecutive_failures == 0 {
+        return Err("needs `consecutive_failures` of at least 1".into());
+    }
+    if outliers.base_ejection_time.is_zero()
+        || outliers.base_ejection_time > outliers.max_ejection_time
+    {
+        return Err("needs a non-zero `base_ejection_time` up to `max_ejection_time`".into());
+    }
+    if outliers.max_ejected_percent > 100 {
+        return Err("can't eject more than 100% of the servers".into());
+    }
+    Ok(())
+}
+
+fn check_retries(retries: &RetryConfig) -> Result<(), String> {
+    if retries.backoff > retries.max_backoff {
+        return Err("can't have a `backoff` above `max_backoff`".into());
+    }
+    if !retries
+        .retry_on_status
+        .iter()
+        .all(|status| (100..600).contains(status))
+    {
+        return Err("`retry_on_status` must list valid status codes".into());
+    }
+    Ok(())
+}
+
+fn check_timeouts(timeouts: &TimeoutsConfig) -> Result<(), String> {
+    if [timeouts.connect, timeouts.first_byte, timeouts.client_body]
+        .iter()
+        .chain(&timeouts.total)
+        .any(Duration::is_zero)
+    {
+        return Err("can't have a zero timeout".into());
+    }
+    Ok(())
+}
+
+fn check_redirects(redirects: &RedirectsConfig) -> Result<(), String> {
+    let allow_hosts = match redirects {
+        RedirectsConfig::Hosts { allow_hosts } => allow_hosts,
+        RedirectsConfig::Mode(_) => return Ok(()),
+    };
+    // Only a whole leading label can be a wildcard.
+    let host = allow_hosts
+        .iter()
+        .find(|host| host.strip_prefix("*.").unwrap_or(host).contains('*'));
+    match host {
+        Some(host) => Err(format!(
+            "can't allow `{}`, only `*.` may start a host",
+            host
+        )),
+        None => Ok(()),
+    }
+}
+
+fn check_balance(balance: &BalanceConfig) -> Result<(), String> {
+    match balance {
+        BalanceConfig::Hash {
+            hash: HashKey::ClientIp,
+            name: Some(_),
+        } => Err("can't set a `name` when hashing on `client_ip`".into()),
+        BalanceConfig::Hash { hash, name: None } if *hash != HashKey::ClientIp => {
+            Err("needs the `name` of the header or cookie to hash on".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+impl RouteConfig {
+    /// The matcher of this route, for error messages.
+    pub fn describe(&self) -> &str {
+        self.path
+            .as_deref()
+            .or(self.path_prefix.as_deref())
+            .or_else(|| self.path_regex.as_ref().map(Regex::as_str))
+            .unwrap_or("<no path>")
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Config, Error> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BalanceConfig, BindAddress, Config, HashKey, RedirectMode, RedirectsConfig};
+
+    const EXAMPLE: &str = r#"
+        [[listeners]]
+        address = "127.0.0.1:1337"
+
+        [[certificates]]
+        domain = "localhost"
+        cert = "cert.pem"
+        key = "privkey.pem"
+
+        [upstreams.app]
+        url = "http://localhost:3000"
+
+        [[hosts]]
+        names = ["localhost"]
+        upstream = "app"
+    "#;
+
+    #[test]
+    fn parses_example() {
+        let config: Config = EXAMPLE.parse().unwrap();
+        assert_eq!(
+            config.listeners[0].address,
+            "127.0.0.1:1337".parse().unwrap()
+        );
+        assert_eq!(config.hosts[0].names, ["localhost"]);
+        assert_eq!(
+            "http://localhost:3000/",
+            config.upstreams["app"].url.as_ref().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_upstream() {
+        let config = EXAMPLE.replace("upstream = \"app\"", "upstream = \"api\"");
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: host `localhost` references unknown upstream `api`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_upstream_with_path() {
+        let config = EXAMPLE.replace("localhost:3000", "localhost:3000/app");
+        assert!(config.parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn rejects_second_default_host() {
+        let config = format!(
+            "{}\n{}",
+            EXAMPLE.replace("upstream = \"app\"", "upstream = \"app\"\ndefault = true"),
+            "[[hosts]]\nupstream = \"app\"\ndefault = true"
+        );
+        assert!(config.parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_route() {
+        let config = format!(
+            "{}\n{}",
+            EXAMPLE, "[[hosts.routes]]\npath = \"/a\"\npath_prefix = \"/b\"\nupstream = \"app\""
+        );
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: route `/a` of host `localhost` must set exactly one of \
+             `path`, `path_prefix` and `path_regex`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_regex_rewrite_without_leading_slash() {
+        let config = format!(
+            "{}\n{}",
+            EXAMPLE,
+            "[[hosts.routes]]\npath_regex = \"^/a/(.*)$\"\nupstream = \"app\"\nrewrite = \"$1\""
+        );
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: route `^/a/(.*)$` of host `localhost` must `rewrite` to a path \
+             starting with `/`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn parses_redirect_policies() {
+        let config = format!(
+            "{}\n{}",
+            EXAMPLE.replace(
+                "[[listeners]]",
+                "[defaults]\nredirects = \"same_host\"\n\n[[listeners]]"
+            ),
+            "[[hosts.routes]]\npath = \"/a\"\nupstream = \"app\"\n\
+             redirects = { allow_hosts = [\"*.example.com\"] }"
+        );
+        let config: Config = config.parse().unwrap();
+        assert!(matches!(
+            config.defaults.redirects,
+            RedirectsConfig::Mode(RedirectMode::SameHost)
+        ));
+        assert!(matches!(
+            config.hosts[0].routes[0].redirects,
+            Some(RedirectsConfig::Hosts { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_wildcards_not_covering_a_whole_label() {
+        let config = EXAMPLE.replace(
+            "[[listeners]]",
+            "[defaults]\nredirects = { allow_hosts = [\"*example.com\"] }\n\n[[listeners]]",
+        );
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: `defaults.redirects` can't allow `*example.com`, only `*.` may start a host",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn parses_unix_addresses() {
+        let config = EXAMPLE
+            .replace(
+                "127.0.0.1:1337\"",
+                "unix:/run/proxy.sock\"\nprotocol = \"http\"",
+            )
+            .replace("http://localhost:3000", "unix:/run/app.sock");
+        let config: Config = config.parse().unwrap();
+        assert_eq!(
+            config.listeners[0].address,
+            BindAddress::Unix("/run/proxy.sock".into())
+        );
+        let (url, _) = config.upstreams["app"].servers().next().unwrap();
+        assert_eq!(Some("unix"), url.scheme_str());
+    }
+
+    #[test]
+    fn parses_server_pools() {
+        let config = format!(
+            "{}\n{}",
+            EXAMPLE.replace(
+                "url = \"http://localhost:3000\"",
+                "servers = [{ url = \"http://a\", weight = 2 }, { url = \"unix:/run/b.sock\" }]"
+            ),
+            "[[hosts.routes]]\npath = \"/a\"\nupstream = \"app\"\n\
+             balance = { hash = \"cookie\", name = \"session\" }"
+        );
+        let config: Config = config.parse().unwrap();
+        let weights: Vec<u32> = config.upstreams["app"].servers().map(|(_, w)| w).collect();
+        assert_eq!(weights, [2, 1]);
+        assert!(matches!(
+            config.hosts[0].routes[0].balance,
+            Some(BalanceConfig::Hash {
+                hash: HashKey::Cookie,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn parses_health_checks() {
+        let config = EXAMPLE.replace(
+            "url = \"http://localhost:3000\"",
+            "url = \"http://localhost:3000\"\n\
+             health_check = { path = \"/health\", expect_body = \"ok\", interval = \"5s\" }",
+        );
+        let config: Config = config.parse().unwrap();
+        let check = config.upstreams["app"].health_check.as_ref().unwrap();
+        assert_eq!(check.interval, std::time::Duration::from_secs(5));
+        assert_eq!((check.rise, check.fall), (2, 3));
+
+        let config = EXAMPLE.replace(
+            "url = \"http://localhost:3000\"",
+            "url = \"http://localhost:3000\"\n\
+             health_check = { protocol = \"tcp\", path = \"/health\" }",
+        );
+        assert!(config.parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn rejects_cookie_hash_without_name() {
+        let config = EXAMPLE.replace(
+            "[[listeners]]",
+            "[defaults]\nbalance = { hash = \"cookie\" }\n\n[[listeners]]",
+        );
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: `defaults.balance` needs the `name` of the header or cookie to hash on",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn picks_acme_domains_and_checks_challenge_listener() {
+        let config = EXAMPLE
+            .replace(
+                "names = [\"localhost\"]",
+                "names = [\"localhost\", \"example.com\"]",
+            )
+            .replace(
+                "[[listeners]]",
+                "[acme]\nstorage = \"acme\"\nchallenge = \"tls-alpn-01\"\n\n[[listeners]]",
+            );
+        let parsed: Config = config.parse().unwrap();
+        // `localhost` has a certificate of its own.
+        assert_eq!(parsed.acme_domains(), ["example.com"]);
+
+        let config = config.replace("tls-alpn-01", "http-01");
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: `acme` http-01 challenges need a TCP listener with `protocol = \"http\"`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn leaves_wildcards_out_of_acme_domains() {
+        let config = EXAMPLE
+            .replace(
+                "names = [\"localhost\"]",
+                "names = [\"localhost\", \"*.example.com\", \"example.com\"]",
+            )
+            .replace(
+                "[[listeners]]",
+                "[acme]\nstorage = \"acme\"\nchallenge = \"tls-alpn-01\"\n\n[[listeners]]",
+            );
+        let parsed: Config = config.parse().unwrap();
+        assert_eq!(parsed.acme_domains(), ["example.com"]);
+
+        let config = config.replace(
+            "storage = \"acme\"",
+            "storage = \"acme\"\ndomains = [\"*.example.com\"]",
+        );
+        let err = config.parse::<Config>().unwrap_err();
+        assert_eq!(
+            "invalid config: `acme` can't get a certificate for wildcard `*.example.com`",
+            err.to_string()
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use crate::config::{TlsVersion, UpstreamTlsConfig};
+use crate::errors::Error;
+use crate::x509;
+use crate::GenericError;
+use futures::future::BoxFuture;
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder, MaybeHttpsStream};
+use log::warn;
+use rustls_client::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls_client::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls_client::TlsConnector;
+
+/// Scheme of the request uris for upstreams listening on a Unix socket. The
+/// socket path is hex encoded into the host, since hyper needs an authority
+/// to pool connections by.
+const UNIX_SCHEME: &str = "unix";
+
+/// Connects to upstreams over TCP, with or without TLS, or over Unix sockets.
+/// Connecting fails with a `TimedOut` error after `connect_timeout`.
+#[derive(Clone)]
+pub struct Connector {
+    https: HttpsConnector<HttpConnector>,
+    http: HttpConnector,
+    tls: Option<UpstreamTls>,
+    connect_timeout: Option<Duration>,
+}
+
+impl Connector {
+    /// Servers of the upstream that `tls` belongs to are connected to with
+    /// its settings, everything else with the system's roots.
+    pub fn new(connect_timeout: Option<Duration>, tls: Option<UpstreamTls>) -> Connector {
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .build();
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+
+        Connector {
+            https,
+            http,
+            tls,
+            connect_timeout,
+        }
+    }
+}
+
+/// The TLS settings of an upstream, for connecting to its `https://`
+/// servers.
+#[derive(Clone)]
+pub struct UpstreamTls {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName>,
+    /// Authorities of the upstream's `https://` servers.
+    servers: Arc<HashSet<String>>,
+}
+
+impl UpstreamTls {
+    pub fn load<'a>(
+        upstream: &str,
+        config: &UpstreamTlsConfig,
+        servers: impl Iterator<Item = &'a Uri>,
+    ) -> Result<UpstreamTls, Error> {
+        let tls_error =
+            |reason: String| Error::Config(format!("upstream `{}` tls {}", upstream, reason));
+
+        let versions: &[_] = match config.min_version {
+            TlsVersion::Tls12 => &[
+                &rustls_client::version::TLS13,
+                &rustls_client::version::TLS12,
+            ],
+            TlsVersion::Tls13 => &[&rustls_client::version::TLS13],
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .map_err(|e| tls_error(e.to_string()))?;
+        let verifier: Arc<dyn ServerCertVerifier> = if config.insecure_skip_verify {
+            warn!(
+                "upstream `{}` accepts any certificate, `insecure_skip_verify` is set",
+                upstream
+            );
+            Arc::new(NoVerification)
+        } else {
+            let roots = roots(config.ca.as_deref()).map_err(tls_error)?;
+            Arc::new(WebPkiVerifier::new(roots, None))
+        };
+        let builder = builder.with_custom_certificate_verifier(verifier);
+        let mut client_config = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => {
+                let (chain, key) = client_certificate(cert, key).map_err(tls_error)?;
+                builder
+                    .with_single_cert(chain, key)
+                    .map_err(|e| tls_error(format!("`{}` {}", cert.display(), e)))?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let server_name = match &config.server_name {
+            Some(name) => Some(
+                ServerName::try_from(name.as_str())
+                    .map_err(|_| tls_error(format!("`server_name` `{}` is invalid", name)))?,
+            ),
+            None => None,
+        };
+        let servers = servers
+            .filter(|url| url.scheme() == Some(&http::uri::Scheme::HTTPS))
+            .filter_map(|url| url.authority())
+            .map(|authority| authority.to_string())
+            .collect();
+        Ok(UpstreamTls {
+            config: Arc::new(client_config),
+            server_name,
+            servers: Arc::new(servers),
+        })
+    }
+
+    /// Whether `uri` is one of the upstream's `https://` servers.
+    fn handles(&self, uri: &Uri) -> bool {
+        uri.scheme() == Some(&http::uri::Scheme::HTTPS)
+            && uri
+                .authority()
+                .is_some_and(|authority| self.servers.contains(authority.as_str()))
+    }
+
+    /// The name to send as SNI and check the certificate against.
+    fn server_name(&self, uri: &Uri) -> io::Result<ServerName> {
+        if let Some(name) = &self.server_name {
+            return Ok(name.clone());
+        }
+        let host = uri.host().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ServerName::try_from(host).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is not a valid server name, set `server_name`", host),
+            )
+        })
+    }
+}
+
+impl fmt::Debug for UpstreamTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamTls")
+            .field("server_name", &self.server_name)
+            .field("servers", &self.servers)
+            .finish()
+    }
+}
+
+/// Accepts any server certificate, for `insecure_skip_verify`.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls_client::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The CAs in the PEM bundle at `ca`, or the system's.
+fn roots(ca: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    let path = match ca {
+        Some(path) => path,
+        None => {
+            let native = rustls_native_certs::load_native_certs()
+                .map_err(|e| format!("could not load the system's CAs: {}", e))?;
+            let ders: Vec<Vec<u8>> = native.into_iter().map(|cert| cert.0).collect();
+            roots.add_parsable_certificates(&ders);
+            return Ok(roots);
+        }
+    };
+    for (_, der) in read_pem(path)?
+        .into_iter()
+        .filter(|(label, _)| label == "CERTIFICATE")
+    {
+        roots
+            .add(&Certificate(der))
+            .map_err(|e| format!("`{}` has an invalid CA: {}", path.display(), e))?;
+    }
+    if roots.is_empty() {
+        return Err(format!("`{}` contains no certificates", path.display()));
+    }
+    Ok(roots)
+}
+
+/// The certificate chain and key to authenticate to an upstream with.
+fn client_certificate(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    let chain: Vec<Certificate> = read_pem(cert_path)?
+        .into_iter()
+        .filter(|(label, _)| label == "CERTIFICATE")
+        .map(|(_, der)| Certificate(der))
+        .collect();
+    if chain.is_empty() {
+        return Err(format!(
+            "`{}` contains no certificates",
+            cert_path.display()
+        ));
+    }
+
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|(label, der)| match label.as_str() {
+            "PRIVATE KEY" | "RSA PRIVATE KEY" => Some(Ok(PrivateKey(der))),
+            // Only PKCS#8 ECDSA keys can be loaded, so try the curves the key
+            // could be on.
+            "EC PRIVATE KEY" => Some(
+                x509::sec1_to_pkcs8(&der)
+                    .into_iter()
+                    .map(PrivateKey)
+                    .find(|key| rustls_client::sign::any_supported_type(key).is_ok())
+                    .ok_or("is not a P-256 or P-384 key"),
+            ),
+            "ENCRYPTED PRIVATE KEY" => Some(Err("is encrypted, which isn't supported")),
+            _ => None,
+        })
+        .unwrap_or(Err("contains no private key"))
+        .map_err(|reason| format!("`{}` {}", key_path.display(), reason))?;
+    Ok((chain, key))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let pem = fs::read_to_string(path)
+        .map_err(|e| format!("could not read `{}`: {}", path.display(), e))?;
+    x509::pem_blocks(&pem)
+        .map_err(|e| format!("`{}` is not a valid PEM file: {}", path.display(), e))
+}
+
+/// The scheme and authority of request uris for the upstream at `socket`.
+pub fn unix_base(socket: &Path) -> String {
+    let hex: String = socket
+        .to_string_lossy()
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}://{}", UNIX_SCHEME, hex)
+}
+
+fn socket_path(uri: &Uri) -> io::Result<PathBuf> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid unix socket uri");
+    let hex = uri.host().ok_or_else(invalid)?.as_bytes();
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect::<io::Result<Vec<u8>>>()?;
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| invalid())
+}
+
+impl Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = GenericError;
+    type Future = BoxFuture<'static, Result<Stream, GenericError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.https.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting: Self::Future = if uri.scheme_str() == Some(UNIX_SCHEME) {
+            Box::pin(async move {
+                let path = socket_path(&uri)?;
+                Ok(Stream::Unix(UnixStream::connect(path).await?))
+            })
+        } else if let Some(tls) = self.tls.as_ref().filter(|tls| tls.handles(&uri)) {
+            let connector = TlsConnector::from(tls.config.clone());
+            let server_name = tls.server_name(&uri);
+            let connecting = self.http.call(uri);
+            Box::pin(async move {
+                let server_name = server_name?;
+                let tcp = connecting.await?;
+                let tls = connector.connect(server_name, tcp).await?;
+                Ok(Stream::Tcp(MaybeHttpsStream::from(tls)))
+            })
+        } else {
+            let connecting = self.https.call(uri);
+            Box::pin(async move { Ok(Stream::Tcp(connecting.await?)) })
+        };
+
+        match self.connect_timeout {
+            Some(limit) => Box::pin(async move {
+                match tokio::time::timeout(limit, connecting).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out").into())
+                    }
+                }
+            }),
+            None => connecting,
+        }
+    }
+}
+
+/// A connection to an upstream.
+// Connections are created once and then only used in place, so the size of
+// the TLS variant doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum Stream {
+    Tcp(MaybeHttpsStream<TcpStream>),
+    Unix(UnixStream),
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        match self {
+            Stream::Tcp(s) => s.connected(),
+            Stream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{socket_path, unix_base, UpstreamTls};
+    use crate::config::UpstreamTlsConfig;
+    use hyper::Uri;
+    use rustls_client::ServerName;
+    use std::convert::TryFrom;
+    use std::path::Path;
+
+    #[test]
+    fn round_trips_socket_path() {
+        let base = unix_base(Path::new("/run/app.sock"));
+        let uri = format!("{}/index.html", base).parse().unwrap();
+        assert_eq!(Path::new("/run/app.sock"), socket_path(&uri).unwrap());
+    }
+
+    #[test]
+    fn uses_upstream_tls_for_its_https_servers() {
+        let config = UpstreamTlsConfig {
+            server_name: Some("app.internal".into()),
+            insecure_skip_verify: true,
+            ..UpstreamTlsConfig::default()
+        };
+        let servers: Vec<Uri> = vec![
+            "https://10.0.0.1:8443".parse().unwrap(),
+            "http://10.0.0.2".parse().unwrap(),
+        ];
+        let tls = UpstreamTls::load("app", &config, servers.iter()).unwrap();
+
+        assert!(tls.handles(&"https://10.0.0.1:8443/path".parse().unwrap()));
+        assert!(!tls.handles(&"https://10.0.0.1/path".parse().unwrap()));
+        assert!(!tls.handles(&"http://10.0.0.2/path".parse().unwrap()));
+        assert!(!tls.handles(&"https://example.com/".parse().unwrap()));
+        let uri = "https://10.0.0.1:8443/".parse().unwrap();
+        assert_eq!(
+            ServerName::try_from("app.internal").unwrap(),
+            tls.server_name(&uri).unwrap()
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use crate::config::ErrorPagesConfig;
+use crate::errors::{error_message, Error, X_PROXY_ERROR};
+use crate::proxy::X_REQUEST_ID;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const DEFAULT_HTML: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>Request ID: {{request_id}}</p>
+</body>
+</html>
+";
+
+const DEFAULT_JSON: &str = r#"{"status": {{status}}, "reason": "{{reason}}", "error": "{{error}}", "request_id": "{{request_id}}"}
+"#;
+
+/// What an error page is filled in with.
+pub struct PageVars<'a> {
+    pub status: StatusCode,
+    /// The proxy's own error, `None` for an intercepted upstream response.
+    pub error: Option<&'a Error>,
+    pub request_id: &'a str,
+    pub upstream: Option<&'a str>,
+}
+
+/// The HTML and JSON pages sent for errors on a route.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    html: Arc<Template>,
+    json: Arc<Template>,
+    intercept_upstream_errors: bool,
+}
+
+impl ErrorPages {
+    pub fn load(config: &ErrorPagesConfig) -> Result<ErrorPages, Error> {
+        let load = |path: &Option<PathBuf>, default| match path {
+            Some(path) => Template::load(path),
+            None => Template::parse(default).map_err(Error::Config),
+        };
+
+        Ok(ErrorPages {
+            html: Arc::new(load(&config.html, DEFAULT_HTML)?),
+            json: Arc::new(load(&config.json, DEFAULT_JSON)?),
+            intercept_upstream_errors: config.intercept_upstream_errors,
+        })
+    }
+
+    /// Whether an upstream response is replaced by an error page.
+    pub fn intercepts(&self, res: &Response<Body>) -> bool {
+        self.intercept_upstream_errors && res.status().is_server_error()
+    }
+
+    /// Render the page in the format the client prefers according to its
+    /// `Accept` header, falling back to plain text.
+    pub fn render(&self, accept: Option<&HeaderValue>, vars: &PageVars<'_>) -> Response<Body> {
+        let (content_type, body) = match negotiate(accept) {
+            Format::Html => (
+                "text/html; charset=utf-8",
+                self.html.render(vars, escape_html),
+            ),
+            Format::Json => ("application/json", self.json.render(vars, escape_json)),
+            Format::Text => ("text/plain; charset=utf-8", error_message(vars.status)),
+        };
+
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = vars.status;
+        let headers = res.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        if let Some(error) = vars.error {
+            headers.insert(X_PROXY_ERROR, HeaderValue::from_static(error.code()));
+        }
+        if let Ok(request_id) = HeaderValue::from_str(vars.request_id) {
+            headers.insert(X_REQUEST_ID, request_id);
+        }
+        res
+    }
+}
+
+/// An error page with `{{variable}}` placeholders.
+#[derive(Debug)]
+struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Status,
+    Reason,
+    Error,
+    RequestId,
+    Upstream,
+}
+
+impl Template {
+    fn load(path: &Path) -> Result<Template, Error> {
+        let source = fs::read_to_string(path).map_err(|e| {
+            Error::Config(format!(
+                "could not read error page `{}`: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Template::parse(&source)
+            .map_err(|e| Error::Config(format!("error page `{}`: {}", path.display(), e)))
+    }
+
+    fn parse(mut source: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        while let Some(start) = source.find("{{") {
+            let end = match source[start..].find("}}") {
+                Some(end) => start + end,
+                None => break,
+            };
+            parts.push(Part::Text(source[..start].to_string()));
+            parts.push(match source[start + 2..end].trim() {
+                "status" => Part::Status,
+                "reason" => Part::Reason,
+                "error" => Part::Error,
+                "request_id" => Part::RequestId,
+                "upstream" => Part::Upstream,
+                name => return Err(format!("unknown variable `{}`", name)),
+            });
+            source = &source[end + 2..];
+        }
+        parts.push(Part::Text(source.to_string()));
+
+        Ok(Template { parts })
+    }
+
+    fn render(&self, vars: &PageVars<'_>, escape: fn(&str) -> String) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Status => out.push_str(vars.status.as_str()),
+                Part::Reason => {
+                    out.push_str(&escape(vars.status.canonical_reason().unwrap_or_default()))
+                }
+                Part::Error => out.push_str(vars.error.map_or("", Error::code)),
+                Part::RequestId => out.push_str(&escape(vars.request_id)),
+                Part::Upstream => out.push_str(&escape(vars.upstream.unwrap_or_default())),
+            }
+        }
+        out
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Html,
+    Json,
+    Text,
+}
+
+/// Pick the format the client accepts with the highest quality, preferring
+/// HTML on ties.
+fn negotiate(accept: Option<&HeaderValue>) -> Format {
+    let accept = match accept.and_then(|accept| accept.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Format::Html,
+    };
+
+    let mut best = (0.0, Format::Text);
+    for (format, media) in [
+        (Format::Html, "text/html"),
+        (Format::Json, "application/json"),
+        (Format::Text, "text/plain"),
+    ] {
+        let q = quality(accept, media);
+        if q > best.0 {
+            best = (q, format);
+        }
+    }
+    best.1
+}
+
+/// The quality the most specific range in `accept` gives to `media`.
+fn quality(accept: &str, media: &str) -> f32 {
+    let any_subtype = format!("{}/*", media.split('/').next().unwrap_or_default());
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let range = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let specificity = if range == media {
+            2
+        } else if range == any_subtype {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let q = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, ErrorPages, Format, PageVars, Template};
+    use crate::config::ErrorPagesConfig;
+    use crate::errors::Error;
+    use hyper::header::HeaderValue;
+    use hyper::StatusCode;
+
+    #[test]
+    fn negotiates_format() {
+        let format = |accept| negotiate(Some(&HeaderValue::from_static(accept)));
+        assert_eq!(negotiate(None), Format::Html);
+        assert_eq!(format("*/*"), Format::Html);
+        assert_eq!(format("application/json"), Format::Json);
+        assert_eq!(format("text/html;q=0.5, application/*"), Format::Json);
+        assert_eq!(format("image/png"), Format::Text);
+    }
+
+    #[test]
+    fn renders_escaped_variables() {
+        let template = Template::parse("<p>{{ status }} {{request_id}} {{upstream}}</p>").unwrap();
+        let vars = PageVars {
+            status: StatusCode::BAD_GATEWAY,
+            error: None,
+            request_id: "<script>",
+            upstream: Some("app"),
+        };
+        assert_eq!(
+            template.render(&vars, super::escape_html),
+            "<p>502 &lt;script&gt; app</p>"
+        );
+        assert!(Template::parse("{{nope}}").is_err());
+    }
+
+    #[test]
+    fn sets_error_headers() {
+        let pages = ErrorPages::load(&ErrorPagesConfig::default()).unwrap();
+        let error = Error::NoHealthyUpstream("app".into());
+        let res = pages.render(
+            Some(&HeaderValue::from_static("application/json")),
+            &PageVars {
+                status: error.status(),
+                error: Some(&error),
+                request_id: "abc",
+                upstream: Some("app"),
+            },
+        );
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["x-proxy-error"], "no_healthy_upstream");
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(res.headers()["x-request-id"], "abc");
+    }
+}
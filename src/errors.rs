@@ -1,16 +1,27 @@
+use crate::metrics;
+use crate::router::RouteError;
+use crate::timeout::{ClientBodyTimedOut, Timeout};
 use http::{status, StatusCode};
 use hyper::{Body, Response};
-use log::error;
 use std::io;
 
+/// Header telling clients which of the proxy's errors they got, as opposed
+/// to an error response from the upstream itself.
+pub const X_PROXY_ERROR: &str = "x-proxy-error";
+
 pub fn send_error_res(code: status::StatusCode) -> Result<Response<Body>, http::Error> {
-    let msg = match code {
+    Response::builder()
+        .status(code)
+        .body(Body::from(error_message(code)))
+}
+
+/// The plain text body of an error response.
+pub fn error_message(code: StatusCode) -> String {
+    match code {
         StatusCode::BAD_GATEWAY => format!("{}: BAD_GATEWAY", code.as_u16()),
         StatusCode::BAD_REQUEST => format!("{}: BAD_REQUEST", code.as_u16()),
         _ => format!("{}", code).to_uppercase(),
-    };
-
-    Response::builder().status(code).body(Body::from(msg))
+    }
 }
 
 use thiserror::Error;
@@ -30,4 +41,217 @@ pub enum Error {
 
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] http::uri::InvalidUri),
+
+    #[error("invalid config: {0}")]
+    Config(String),
+
+    #[error("could not parse config: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("certificate for `{domain}`: {reason}")]
+    Certificate { domain: String, reason: String },
+
+    #[error("acme: {0}")]
+    Acme(String),
+
+    #[error("request has no host")]
+    MissingHost,
+
+    #[error("route doesn't accept the client's certificate")]
+    ClientCertRejected,
+
+    #[error("{0}")]
+    Route(#[from] RouteError),
+
+    #[error("request body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+
+    #[error("could not read request body: {0}")]
+    ClientBody(#[source] hyper::Error),
+
+    #[error("no healthy server in upstream `{0}`")]
+    NoHealthyUpstream(String),
+
+    #[error("could not connect to upstream: {0}")]
+    UpstreamConnect(#[source] hyper::Error),
+
+    #[error("timed out {0}")]
+    Timeout(Timeout),
+
+    #[error("upstream closed the connection before responding: {0}")]
+    UpstreamReset(#[source] hyper::Error),
+
+    #[error("invalid response from upstream: {0}")]
+    UpstreamResponse(#[source] hyper::Error),
+
+    #[error("upstream redirects in a loop")]
+    RedirectLoop,
+
+    #[error("upstream redirected more than {0} times")]
+    TooManyRedirects(usize),
+}
+
+impl Error {
+    /// A timeout of kind `timeout`, counted in the metrics.
+    pub fn timeout(timeout: Timeout) -> Error {
+        metrics::timed_out(timeout);
+        Error::Timeout(timeout)
+    }
+
+    /// Classify an error reading the client's request body.
+    pub fn client_body(e: hyper::Error) -> Error {
+        if has_source::<ClientBodyTimedOut>(&e) {
+            Error::timeout(Timeout::ClientBody)
+        } else {
+            Error::ClientBody(e)
+        }
+    }
+
+    /// Classify an error of the client sending requests upstream.
+    pub fn upstream(e: hyper::Error) -> Error {
+        if has_source::<ClientBodyTimedOut>(&e) {
+            Error::timeout(Timeout::ClientBody)
+        } else if is_timeout(&e) && e.is_connect() {
+            Error::timeout(Timeout::Connect)
+        } else if is_timeout(&e) {
+            Error::timeout(Timeout::FirstByte)
+        } else if e.is_connect() {
+            Error::UpstreamConnect(e)
+        } else if e.is_parse() {
+            Error::UpstreamResponse(e)
+        } else if is_client_body(&e) {
+            Error::ClientBody(e)
+        } else {
+            Error::UpstreamReset(e)
+        }
+    }
+
+    /// Whether the error is the upstream's fault, rather than the client's
+    /// or the proxy's.
+    pub fn is_upstream(&self) -> bool {
+        matches!(
+            self,
+            Error::UpstreamConnect(_)
+                | Error::UpstreamReset(_)
+                | Error::UpstreamResponse(_)
+                | Error::RedirectLoop
+                | Error::TooManyRedirects(_)
+        ) || matches!(self, Error::Timeout(timeout) if !timeout.is_client())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::HTTP(_)
+            | Error::IO(_)
+            | Error::InvalidUri(_)
+            | Error::Config(_)
+            | Error::Toml(_)
+            | Error::Certificate { .. }
+            | Error::Acme(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::MissingHost | Error::ClientBody(_) => StatusCode::BAD_REQUEST,
+            Error::ClientCertRejected => StatusCode::FORBIDDEN,
+            Error::Route(e) => e.status(),
+            Error::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::NoHealthyUpstream(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Timeout(timeout) if timeout.is_client() => StatusCode::REQUEST_TIMEOUT,
+            Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::RedirectLoop => StatusCode::LOOP_DETECTED,
+            Error::UpstreamConnect(_)
+            | Error::UpstreamReset(_)
+            | Error::UpstreamResponse(_)
+            | Error::TooManyRedirects(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Short, stable name of the error, sent in the `X-Proxy-Error` header.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::HTTP(_)
+            | Error::IO(_)
+            | Error::InvalidUri(_)
+            | Error::Config(_)
+            | Error::Toml(_)
+            | Error::Certificate { .. }
+            | Error::Acme(_) => "internal_error",
+            Error::MissingHost => "missing_host",
+            Error::ClientCertRejected => "client_cert_rejected",
+            Error::Route(RouteError::NotFound) => "no_route",
+            Error::Route(RouteError::Misdirected) => "misdirected_request",
+            Error::BodyTooLarge(_) => "body_too_large",
+            Error::ClientBody(_) => "client_body_error",
+            Error::NoHealthyUpstream(_) => "no_healthy_upstream",
+            Error::UpstreamConnect(_) => "upstream_connect_failed",
+            Error::Timeout(Timeout::ClientBody) => "client_body_timeout",
+            Error::Timeout(Timeout::Connect) => "upstream_connect_timeout",
+            Error::Timeout(Timeout::FirstByte) => "upstream_timeout",
+            Error::Timeout(Timeout::Total) => "request_timeout",
+            Error::Timeout(_) => "client_timeout",
+            Error::UpstreamReset(_) => "upstream_reset",
+            Error::UpstreamResponse(_) => "upstream_invalid_response",
+            Error::RedirectLoop => "redirect_loop",
+            Error::TooManyRedirects(_) => "too_many_redirects",
+        }
+    }
+}
+
+fn is_timeout(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Whether sending the request failed on reading the client's body, which
+/// hyper reports as the error of the body stream, with our error as source.
+fn is_client_body(e: &hyper::Error) -> bool {
+    use std::error::Error as _;
+    e.is_body_write_aborted()
+        || e.is_user() && e.source().is_some_and(|source| source.is::<hyper::Error>())
+}
+
+fn has_source<E: std::error::Error + 'static>(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.is::<E>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use crate::router::RouteError;
+    use crate::timeout::Timeout;
+    use http::StatusCode;
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        let error = Error::NoHealthyUpstream("app".into());
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.code(), "no_healthy_upstream");
+
+        let error = Error::Timeout(Timeout::ClientBody);
+        assert_eq!(error.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(!error.is_upstream());
+        let error = Error::Timeout(Timeout::Connect);
+        assert_eq!(error.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(error.code(), "upstream_connect_timeout");
+
+        let error = Error::from(RouteError::Misdirected);
+        assert_eq!(error.status(), StatusCode::MISDIRECTED_REQUEST);
+        assert_eq!(error.code(), "misdirected_request");
+
+        let error = Error::ClientCertRejected;
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error.code(), "client_cert_rejected");
+    }
 }
@@ -1,7 +1,18 @@
+use crate::config::{RedirectMode, RedirectsConfig, TimeoutsConfig};
+use crate::error_pages::ErrorPages;
+use crate::retry::RetryPolicy;
+use crate::timeout::Timeout;
 use crate::ClientType;
-use crate::{errors::Error, send_error_res, uri::UriExt};
-use bytes::Bytes;
+use crate::{errors::Error, uri::UriExt};
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
 use hyper::{header, Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
 
 pub fn remove_sensitive_headers(headers: &mut HeaderMap, next: &Uri, previous: &Uri) {
     if !next.is_same_host(previous) {
@@ -12,114 +23,710 @@ pub fn remove_sensitive_headers(headers: &mut HeaderMap, next: &Uri, previous: &
     }
 }
 
-struct State {
+/// Decides whether to follow a redirect from the original uri to the next one.
+pub type RedirectPredicate = dyn Fn(&Uri, &Uri) -> bool + Send + Sync;
+
+/// Which upstream redirects the proxy follows itself instead of handing them
+/// to the client.
+#[derive(Clone)]
+pub enum RedirectPolicy {
+    /// Pass every redirect through to the client.
+    Off,
+    /// Follow redirects that stay on the upstream's host and port.
+    SameHost,
+    /// Follow every redirect.
+    Any,
+    /// Follow a redirect if the predicate returns true.
+    Custom(Arc<RedirectPredicate>),
+}
+
+impl RedirectPolicy {
+    pub fn from_config(config: &RedirectsConfig) -> RedirectPolicy {
+        match config {
+            RedirectsConfig::Mode(RedirectMode::Off) => RedirectPolicy::Off,
+            RedirectsConfig::Mode(RedirectMode::SameHost) => RedirectPolicy::SameHost,
+            RedirectsConfig::Mode(RedirectMode::Any) => RedirectPolicy::Any,
+            RedirectsConfig::Hosts { allow_hosts } => {
+                let allow_hosts: Vec<String> = allow_hosts
+                    .iter()
+                    .map(|host| host.to_ascii_lowercase())
+                    .collect();
+                RedirectPolicy::Custom(Arc::new(move |_, next| {
+                    let host = next.host().unwrap_or_default().to_ascii_lowercase();
+                    allow_hosts
+                        .iter()
+                        .any(|allowed| allows_host(allowed, &host))
+                }))
+            }
+        }
+    }
+
+    fn allows(&self, original: &Uri, next: &Uri) -> bool {
+        match self {
+            RedirectPolicy::Off => false,
+            RedirectPolicy::SameHost => next.is_same_host(original),
+            RedirectPolicy::Any => true,
+            RedirectPolicy::Custom(predicate) => predicate(original, next),
+        }
+    }
+}
+
+/// Whether `host` is `allowed`, or a subdomain of it if `allowed` starts
+/// with `*.`.
+fn allows_host(allowed: &str, host: &str) -> bool {
+    match allowed.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => host == allowed,
+    }
+}
+
+impl fmt::Debug for RedirectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedirectPolicy::Off => f.write_str("Off"),
+            RedirectPolicy::SameHost => f.write_str("SameHost"),
+            RedirectPolicy::Any => f.write_str("Any"),
+            RedirectPolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Per route settings for sending a request upstream.
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    pub redirects: RedirectPolicy,
+    pub max_redirects: usize,
+    pub max_buffered_body: usize,
+    pub retries: RetryPolicy,
+    pub error_pages: ErrorPages,
+    pub timeouts: TimeoutsConfig,
+    /// Client connecting with the route's connect timeout.
+    pub client: ClientType,
+}
+
+struct State<'a> {
     method: Method,
     uri: Uri,
     version: http::Version,
     headers: HeaderMap,
-    body: Bytes,
+    /// The client's body, streamed as is to the first upstream request.
+    stream: Option<Body>,
+    /// What was copied of the body while streaming it.
+    capture: Option<Arc<Mutex<Capture>>>,
+    /// The whole body, once it is known the request can be replayed after a
+    /// redirect.
+    replay: Option<Bytes>,
+    original: Uri,
+    visited: HashSet<Uri>,
     remaining_redirects: usize,
+    options: &'a RequestOptions,
 }
 
 enum Decision {
     Continue,
     Return,
+    Fail(Error),
 }
 
-impl State {
-    pub fn new<B>(req: &mut Request<B>, max_redirects: usize) -> State {
-        let mut state = State {
-            method: req.method().clone(),
-            uri: req.uri().clone(),
-            version: req.version(),
-            headers: HeaderMap::new(),
-            body: Bytes::new(),
-            remaining_redirects: max_redirects,
-        };
-        state.headers = ::std::mem::replace(req.headers_mut(), HeaderMap::new());
-        state
+impl<'a> State<'a> {
+    pub fn new(req: Request<Body>, options: &'a RequestOptions) -> State<'a> {
+        let (parts, body) = req.into_parts();
+        State {
+            method: parts.method,
+            original: parts.uri.clone(),
+            visited: std::iter::once(parts.uri.clone()).collect(),
+            uri: parts.uri,
+            version: parts.version,
+            headers: parts.headers,
+            stream: Some(body),
+            capture: None,
+            replay: None,
+            remaining_redirects: options.max_redirects,
+            options,
+        }
+    }
+
+    /// Whether a redirect may make us send the body a second time. Only
+    /// 307/308 keep the body, and any method can get one of those.
+    fn may_replay(&self) -> bool {
+        !matches!(self.options.redirects, RedirectPolicy::Off) && self.remaining_redirects > 0
     }
 
-    pub fn create_request(&self) -> Request<Body> {
+    /// The next request to send upstream, and word of when its body was
+    /// sent. Bodies held in memory count as sent right away.
+    pub fn create_request(&mut self) -> (Request<Body>, oneshot::Receiver<()>) {
+        let (sent, body_sent) = oneshot::channel();
+        let body = match self.stream.take() {
+            Some(body) if body.is_end_stream() => {
+                self.replay = Some(Bytes::new());
+                let _ = sent.send(());
+                body
+            }
+            Some(body) => {
+                let length = content_length(&self.headers);
+                let capture = if self.may_replay() {
+                    let capture = Capture::new(self.options.max_buffered_body, length);
+                    let capture = Arc::new(Mutex::new(capture));
+                    self.capture = Some(capture.clone());
+                    Some(capture)
+                } else {
+                    None
+                };
+                upload(body, length, capture, sent)
+            }
+            None => {
+                let _ = sent.send(());
+                Body::from(self.replay.clone().unwrap_or_default())
+            }
+        };
         let mut req = Request::builder()
             .uri(self.uri.clone())
             .method(self.method.clone())
-            .version(self.version.clone())
-            .body(Body::from(self.body.clone()))
+            .version(self.version)
+            .body(body)
             .unwrap();
 
         req.headers_mut().clone_from(&self.headers);
-        req
+        (req, body_sent)
     }
 
-    pub fn follow_redirect(&mut self, res: &Response<Body>) -> Result<Decision, Error> {
-        self.remaining_redirects -= 1;
+    /// Switch to a bodiless GET, as required for 303 and done by every
+    /// client for a POST answered with 301/302.
+    fn drop_body(&mut self) {
+        if self.method != Method::HEAD {
+            self.method = Method::GET;
+        }
+        self.stream = None;
+        self.replay = Some(Bytes::new());
+        self.headers.remove(header::CONTENT_LENGTH);
+        self.headers.remove(header::CONTENT_TYPE);
+        self.headers.remove(header::TRANSFER_ENCODING);
+    }
 
-        if self.remaining_redirects == 0 {
+    pub fn follow_redirect(
+        &mut self,
+        res: &Response<Body>,
+        keep_body: bool,
+    ) -> Result<Decision, Error> {
+        let location = match res.headers().get(header::LOCATION) {
+            Some(location) => location,
+            None => return Ok(Decision::Return),
+        };
+        let next = self.uri.compute_redirect(location.to_owned())?;
+        if !self.options.redirects.allows(&self.original, &next) {
             return Ok(Decision::Return);
         }
 
-        if let Some(location) = res.headers().get(header::LOCATION) {
-            let next = self.uri.compute_redirect(location.to_owned())?;
-            remove_sensitive_headers(&mut self.headers, &next, &self.uri);
-            self.uri = next;
+        if keep_body && self.replay.is_none() {
+            if let Some(capture) = self.capture.take() {
+                let mut capture = capture.lock().unwrap();
+                if capture.too_large {
+                    return Ok(Decision::Fail(Error::BodyTooLarge(capture.limit)));
+                }
+                self.replay = capture.take();
+            }
+        }
+        if keep_body && self.replay.is_none() {
+            // The upstream answered before all of the body was sent, so let
+            // the client follow the redirect.
+            return Ok(Decision::Return);
+        }
+        if !self.visited.insert(next.clone()) {
+            return Ok(Decision::Fail(Error::RedirectLoop));
+        }
+        if self.remaining_redirects == 0 {
+            return Ok(Decision::Fail(Error::TooManyRedirects(
+                self.options.max_redirects,
+            )));
+        }
+        self.remaining_redirects -= 1;
 
-            Ok(Decision::Continue)
-        } else {
-            Ok(Decision::Return)
+        if !keep_body {
+            self.drop_body();
         }
+        remove_sensitive_headers(&mut self.headers, &next, &self.uri);
+        // The `Host` we were sent names this upstream, not the next one, which
+        // hyper names itself.
+        if next.authority() != self.uri.authority() {
+            self.headers.remove(header::HOST);
+        }
+        self.uri = next;
+
+        Ok(Decision::Continue)
     }
 
     pub fn handle_response(&mut self, res: &Response<Body>) -> Result<Decision, Error> {
+        if matches!(self.options.redirects, RedirectPolicy::Off) {
+            return Ok(Decision::Return);
+        }
         match res.status() {
-            StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT => {
-                self.follow_redirect(res)
+            StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => {
+                self.follow_redirect(res, true)
+            }
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND => {
+                let keep_body = self.method != Method::POST;
+                self.follow_redirect(res, keep_body)
             }
-            StatusCode::FOUND | StatusCode::TEMPORARY_REDIRECT => self.follow_redirect(res),
-            StatusCode::SEE_OTHER => self.follow_redirect(res),
+            StatusCode::SEE_OTHER => self.follow_redirect(res, false),
             _ => Ok(Decision::Return),
         }
     }
 }
 
-pub async fn request(req: &mut Request<Body>, client: ClientType) -> Response<Body> {
-    let remaining = 10;
-
-    let mut state = State::new(req, 10);
+/// A copy of the body streamed with the first request, kept in case a
+/// 307/308 needs it again. Dropped once it grows past the limit.
+struct Capture {
+    buf: Option<BytesMut>,
+    limit: usize,
+    /// The client's `Content-Length`. hyper stops reading a body once that
+    /// many bytes were sent, so its end is never seen.
+    length: Option<u64>,
+    complete: bool,
+    /// Whether the body is larger than the limit, which a 307/308 is then
+    /// answered with a 413 for.
+    too_large: bool,
+}
 
-    let mut res = client
-        .request(state.create_request())
-        .await
-        .unwrap_or_else(move |err| {
-            if err.is_closed() {
-                send_error_res(http::StatusCode::BAD_GATEWAY).unwrap()
-            } else if err.is_connect() {
-                send_error_res(http::StatusCode::BAD_GATEWAY).unwrap()
+impl Capture {
+    fn new(limit: usize, length: Option<u64>) -> Capture {
+        let too_large = length.is_some_and(|length| length > limit as u64);
+        Capture {
+            buf: if too_large {
+                None
             } else {
-                send_error_res(http::StatusCode::BAD_GATEWAY).unwrap()
+                Some(BytesMut::new())
+            },
+            limit,
+            length,
+            complete: false,
+            too_large,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        let buf = match &mut self.buf {
+            Some(buf) if buf.len() + chunk.len() <= self.limit => buf,
+            Some(_) => {
+                self.buf = None;
+                self.too_large = true;
+                return;
             }
-        });
+            None => return,
+        };
+        buf.extend_from_slice(chunk);
+        if self.length == Some(buf.len() as u64) {
+            self.complete = true;
+        }
+    }
+
+    /// The whole body, if all of it was copied.
+    fn take(&mut self) -> Option<Bytes> {
+        match self.buf.take() {
+            Some(buf) if self.complete => Some(buf.freeze()),
+            _ => None,
+        }
+    }
+}
+
+/// A client's body on its way upstream.
+struct Upload {
+    body: Body,
+    /// The client's `Content-Length`, and how much of it was sent so far.
+    length: Option<u64>,
+    sent_bytes: u64,
+    capture: Option<Arc<Mutex<Capture>>>,
+    sent: Option<oneshot::Sender<()>>,
+}
+
+impl Upload {
+    fn finish(&mut self) {
+        if let Some(capture) = &self.capture {
+            capture.lock().unwrap().complete = true;
+        }
+        if let Some(sent) = self.sent.take() {
+            let _ = sent.send(());
+        }
+    }
+}
 
-    while remaining > 0 {
-        let client = client.clone();
-        match state.handle_response(&res).unwrap_or(Decision::Continue) {
-            Decision::Continue => {
-                res = client
-                    .request(state.create_request())
-                    .await
-                    .unwrap_or_else(move |err| {
-                        if err.is_closed() {
-                            send_error_res(http::StatusCode::BAD_GATEWAY).unwrap()
-                        } else if err.is_connect() {
-                            send_error_res(http::StatusCode::BAD_GATEWAY).unwrap()
-                        } else {
-                            send_error_res(http::StatusCode::BAD_GATEWAY).unwrap()
-                        }
-                    });
+/// Stream `body` as is, copying it into `capture` on the way, and report on
+/// `sent` once all of it was handed to the upstream connection.
+fn upload(
+    body: Body,
+    length: Option<u64>,
+    capture: Option<Arc<Mutex<Capture>>>,
+    sent: oneshot::Sender<()>,
+) -> Body {
+    let upload = Upload {
+        body,
+        length,
+        sent_bytes: 0,
+        capture,
+        sent: Some(sent),
+    };
+    Body::wrap_stream(futures::stream::unfold(Some(upload), |upload| async move {
+        let mut upload = upload?;
+        match upload.body.data().await {
+            Some(Ok(chunk)) => {
+                if let Some(capture) = &upload.capture {
+                    capture.lock().unwrap().push(&chunk);
+                }
+                // hyper stops reading once `Content-Length` bytes were sent,
+                // so the end of such a body is never seen.
+                upload.sent_bytes += chunk.len() as u64;
+                if upload.length == Some(upload.sent_bytes) {
+                    upload.finish();
+                }
+                Some((Ok(chunk), Some(upload)))
             }
-            Decision::Return => return res,
+            Some(Err(e)) => Some((Err(crate::GenericError::from(e)), None)),
+            None => {
+                upload.finish();
+                None
+            }
+        }
+    }))
+}
+
+/// The `Content-Length` of a request, if it has a valid one.
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse().ok())
+}
+
+/// Read a whole request body into memory. Fails if it is larger than
+/// `limit`, or if the client goes away while sending it.
+pub async fn buffer_body(
+    mut body: Body,
+    headers: &HeaderMap,
+    limit: usize,
+) -> Result<Bytes, Error> {
+    if content_length(headers).is_some_and(|len| len > limit as u64) {
+        return Err(Error::BodyTooLarge(limit));
+    }
+
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(Error::client_body)?;
+        if buf.len() + chunk.len() > limit {
+            return Err(Error::BodyTooLarge(limit));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// Wait for the upstream's response headers, for at most `limit` once the
+/// request body was sent. The upload itself is only limited by how long the
+/// client may pause, so a large body doesn't make a healthy upstream time
+/// out.
+async fn first_byte<T>(
+    res: impl Future<Output = T>,
+    body_sent: oneshot::Receiver<()>,
+    limit: Duration,
+) -> Result<T, Error> {
+    tokio::pin!(res);
+    // The upstream may well answer before it has the whole body.
+    tokio::select! {
+        res = &mut res => return Ok(res),
+        _ = body_sent => {}
+    }
+    tokio::time::timeout(limit, res)
+        .await
+        .map_err(|_| Error::timeout(Timeout::FirstByte))
+}
+
+/// Send a request upstream, following redirects as `options` allow.
+pub async fn request(
+    req: Request<Body>,
+    options: &RequestOptions,
+) -> Result<Response<Body>, Error> {
+    let mut state = State::new(req, options);
+    loop {
+        let (req, body_sent) = state.create_request();
+        let res = options.client.request(req);
+        let res = first_byte(res, body_sent, options.timeouts.first_byte)
+            .await?
+            .map_err(Error::upstream)?;
+
+        // A redirect with a broken `Location` is the client's problem.
+        match state.handle_response(&res).unwrap_or(Decision::Return) {
+            Decision::Continue => {}
+            Decision::Return => return Ok(res),
+            Decision::Fail(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allows_host, request, RedirectPolicy, RequestOptions};
+    use crate::config::{ErrorPagesConfig, RetryBudgetConfig, RetryConfig, TimeoutsConfig};
+    use crate::connector::Connector;
+    use crate::error_pages::ErrorPages;
+    use crate::retry::{RetryBudget, RetryPolicy};
+    use bytes::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::time::sleep;
+
+    /// An upstream redirecting `/<status>` to `/echo`, which answers with the
+    /// method, body and `Host` it got. `/to/<address>` redirects to `/echo`
+    /// on another upstream. `/hang` reads the body and never answers.
+    /// `/loop/a` and `/loop/b` redirect to each other, and `/hops/<n>` to
+    /// `/hops/<n + 1>`.
+    async fn upstream() -> SocketAddr {
+        let make = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let path = req.uri().path().to_string();
+                let redirect = |status: u16, location: &str| {
+                    Response::builder()
+                        .status(status)
+                        .header(header::LOCATION, location)
+                        .body(Body::empty())
+                };
+                let res = match path.trim_start_matches('/').parse::<u16>() {
+                    Ok(status) => redirect(status, "/echo"),
+                    Err(_) if path == "/echo" => {
+                        let method = req.method().clone();
+                        let host = req.headers()[header::HOST].clone();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        Response::builder()
+                            .header("x-method", method.as_str())
+                            .header("x-host", host)
+                            .body(Body::from(format!(
+                                "{} {}",
+                                method,
+                                String::from_utf8_lossy(&body)
+                            )))
+                    }
+                    Err(_) if path.starts_with("/to/") => {
+                        redirect(307, &format!("http://{}/echo", &path["/to/".len()..]))
+                    }
+                    Err(_) if path == "/hang" => {
+                        let _ = hyper::body::to_bytes(req.into_body()).await;
+                        futures::future::pending().await
+                    }
+                    Err(_) if path == "/loop/a" => redirect(302, "/loop/b"),
+                    Err(_) if path == "/loop/b" => redirect(302, "/loop/a"),
+                    Err(_) if path.starts_with("/hops/") => {
+                        let hop: u32 = path["/hops/".len()..].parse().unwrap();
+                        redirect(302, &format!("/hops/{}", hop + 1))
+                    }
+                    Err(_) => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty()),
+                };
+                Ok::<_, Infallible>(res.unwrap())
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn options(redirects: RedirectPolicy, max_redirects: usize) -> RequestOptions {
+        let budget = Arc::new(RetryBudget::new(&RetryBudgetConfig::default()));
+        RequestOptions {
+            redirects,
+            max_redirects,
+            max_buffered_body: 16,
+            retries: RetryPolicy::new(&RetryConfig::default(), budget),
+            error_pages: ErrorPages::load(&ErrorPagesConfig::default()).unwrap(),
+            timeouts: TimeoutsConfig::default(),
+            client: hyper::Client::builder().build(Connector::new(None, None)),
         }
     }
 
-    return res;
+    /// Send `body` to `path`, with a `Content-Length` if `length` is set,
+    /// and return the status and the method and body the upstream saw, or
+    /// the status the proxy would answer with.
+    async fn send(
+        options: &RequestOptions,
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        body: &str,
+        length: bool,
+    ) -> Result<(StatusCode, String), StatusCode> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", addr, path));
+        if length {
+            req = req.header(header::CONTENT_LENGTH, body.len());
+        }
+        let res = request(req.body(Body::from(body.to_string())).unwrap(), options)
+            .await
+            .map_err(|e| e.status())?;
+        let status = res.status();
+        let method = res.headers().get("x-method").cloned();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let seen = match method {
+            Some(method) if body.is_empty() => method.to_str().unwrap().to_string(),
+            _ => String::from_utf8_lossy(&body).into_owned(),
+        };
+        Ok((status, seen))
+    }
+
+    #[tokio::test]
+    async fn streams_bodies_and_replays_them_within_the_limit() {
+        let addr = upstream().await;
+        let options = options(RedirectPolicy::SameHost, 5);
+        let large = "x".repeat(64);
+        let ok = |seen: String| Ok((StatusCode::OK, seen));
+
+        // Larger than `max_buffered_body`, but never needs replaying.
+        assert_eq!(
+            ok(format!("POST {}", large)),
+            send(&options, addr, Method::POST, "/echo", &large, true).await
+        );
+        assert_eq!(
+            ok("POST small".into()),
+            send(&options, addr, Method::POST, "/307", "small", true).await
+        );
+        assert_eq!(
+            ok("PUT small".into()),
+            send(&options, addr, Method::PUT, "/308", "small", false).await
+        );
+        // Too large to replay, with or without a `Content-Length`.
+        assert_eq!(
+            Err(StatusCode::PAYLOAD_TOO_LARGE),
+            send(&options, addr, Method::POST, "/307", &large, true).await
+        );
+        assert_eq!(
+            Err(StatusCode::PAYLOAD_TOO_LARGE),
+            send(&options, addr, Method::PUT, "/308", &large, false).await
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_with_their_method_semantics() {
+        let addr = upstream().await;
+        let options = options(RedirectPolicy::SameHost, 5);
+        let ok = |seen: &str| Ok((StatusCode::OK, seen.to_string()));
+        let send = |method, path, body| send(&options, addr, method, path, body, true);
+
+        // 303 always turns into a GET without a body, except for HEAD.
+        assert_eq!(ok("GET "), send(Method::POST, "/303", "form").await);
+        assert_eq!(ok("GET "), send(Method::PUT, "/303", "data").await);
+        assert_eq!(ok("HEAD"), send(Method::HEAD, "/303", "").await);
+        // Bodies the upstream drops don't count against `max_buffered_body`.
+        let large = "x".repeat(64);
+        assert_eq!(ok("GET "), send(Method::POST, "/303", &large).await);
+        // 301/302 only do so for POST.
+        assert_eq!(ok("GET "), send(Method::POST, "/301", "form").await);
+        assert_eq!(ok("GET "), send(Method::POST, "/302", "form").await);
+        assert_eq!(ok("PUT data"), send(Method::PUT, "/301", "data").await);
+        assert_eq!(ok("HEAD"), send(Method::HEAD, "/302", "").await);
+        // 307/308 keep the method and body.
+        assert_eq!(ok("POST form"), send(Method::POST, "/307", "form").await);
+        assert_eq!(ok("POST form"), send(Method::POST, "/308", "form").await);
+    }
+
+    #[tokio::test]
+    async fn stops_at_loops_and_the_hop_limit() {
+        let addr = upstream().await;
+        let get = |options, path| send(options, addr, Method::GET, path, "", false);
+
+        let same_host = options(RedirectPolicy::SameHost, 3);
+        assert_eq!(
+            Err(StatusCode::LOOP_DETECTED),
+            get(&same_host, "/loop/a").await
+        );
+        assert_eq!(
+            Err(StatusCode::BAD_GATEWAY),
+            get(&same_host, "/hops/0").await
+        );
+
+        let off = options(RedirectPolicy::Off, 3);
+        assert_eq!(StatusCode::FOUND, get(&off, "/hops/0").await.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn times_the_first_byte_from_the_end_of_the_body() {
+        let addr = upstream().await;
+        let mut options = options(RedirectPolicy::SameHost, 5);
+        options.timeouts.first_byte = Duration::from_millis(100);
+        let slow_upload = |path: &str| {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for chunk in &["slow", " ", "upload"] {
+                    sleep(Duration::from_millis(60)).await;
+                    sender.send_data(Bytes::from(*chunk)).await.unwrap();
+                }
+            });
+            let req = Request::post(format!("http://{}{}", addr, path))
+                .body(body)
+                .unwrap();
+            request(req, &options)
+        };
+
+        let _counters = crate::metrics::COUNTERS.lock().await;
+        let res = slow_upload("/echo").await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"POST slow upload");
+
+        let started = Instant::now();
+        let e = slow_upload("/hang").await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() >= Duration::from_millis(280));
+    }
+
+    #[tokio::test]
+    async fn blames_the_client_for_its_broken_body() {
+        let addr = upstream().await;
+        let options = options(RedirectPolicy::SameHost, 5);
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("partial")).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            sender.abort();
+        });
+        let req = Request::post(format!("http://{}/hang", addr))
+            .body(body)
+            .unwrap();
+
+        let e = request(req, &options).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(e.code(), "client_body_error");
+        assert!(!e.is_upstream());
+    }
+
+    #[tokio::test]
+    async fn names_the_next_upstream_in_host() {
+        let (first, second) = (upstream().await, upstream().await);
+        let options = options(RedirectPolicy::Any, 5);
+        let send = |path: String| {
+            let req = Request::get(format!("http://{}{}", first, path))
+                .header(header::HOST, "proxy.example.com")
+                .body(Body::empty())
+                .unwrap();
+            request(req, &options)
+        };
+
+        let res = send("/307".to_string()).await.unwrap();
+        assert_eq!(res.headers()["x-host"], "proxy.example.com");
+        let res = send(format!("/to/{}", second)).await.unwrap();
+        assert_eq!(res.headers()["x-host"], second.to_string().as_str());
+    }
+
+    #[test]
+    fn allows_subdomains_of_wildcards() {
+        assert!(allows_host("example.com", "example.com"));
+        assert!(!allows_host("example.com", "www.example.com"));
+        assert!(allows_host("*.example.com", "www.example.com"));
+        assert!(allows_host("*.example.com", "a.b.example.com"));
+        assert!(!allows_host("*.example.com", "example.com"));
+        assert!(!allows_host("*.example.com", "evilexample.com"));
+        assert!(!allows_host("*.example.com", ".example.com"));
+    }
 }
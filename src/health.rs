@@ -0,0 +1,139 @@
+use crate::config::{HealthCheckConfig, HealthCheckProtocol};
+use crate::connector::Connector;
+use crate::upstream::{Member, Pool};
+use crate::ClientType;
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use hyper::{Body, Request};
+use log::{debug, info, warn};
+use std::sync::{Arc, Weak};
+use tokio::time::{sleep, timeout};
+
+/// Most of a response body searched for `expect_body`.
+const MAX_PROBE_BODY: usize = 64 * 1024;
+
+/// Start probing every member of `pool`, if it has a health check. The
+/// probes stop once the pool is dropped.
+pub fn spawn(pool: &Arc<Pool>) {
+    let check = match &pool.health_check {
+        Some(check) => check,
+        None => return,
+    };
+    // Probes are bounded by the check's own timeout.
+    let connector = Connector::new(None, pool.tls.clone());
+    let client = hyper::Client::builder().build(connector.clone());
+    for member in &pool.members {
+        tokio::spawn(watch(
+            pool.name.clone(),
+            Arc::downgrade(member),
+            check.clone(),
+            Prober {
+                connector: connector.clone(),
+                client: client.clone(),
+            },
+        ));
+    }
+}
+
+#[derive(Clone)]
+struct Prober {
+    connector: Connector,
+    client: ClientType,
+}
+
+async fn watch(pool: String, member: Weak<Member>, check: HealthCheckConfig, mut prober: Prober) {
+    let (mut passed, mut failed) = (0, 0);
+
+    loop {
+        // Only upgraded while probing, so the member can go away in between.
+        let member = match member.upgrade() {
+            Some(member) => member,
+            None => return,
+        };
+
+        let healthy = match timeout(check.timeout, prober.probe(&member, &check)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(reason)) => {
+                debug!(
+                    "health check of `{}` {} failed: {}",
+                    pool, member.url, reason
+                );
+                false
+            }
+            Err(_) => {
+                debug!("health check of `{}` {} timed out", pool, member.url);
+                false
+            }
+        };
+
+        if healthy {
+            passed += 1;
+            failed = 0;
+            if !member.is_healthy() && passed >= check.rise {
+                info!("upstream `{}` server {} is healthy again", pool, member.url);
+                member.set_healthy(true);
+            }
+        } else {
+            failed += 1;
+            passed = 0;
+            if member.is_healthy() && failed >= check.fall {
+                warn!("upstream `{}` server {} is unhealthy", pool, member.url);
+                member.set_healthy(false);
+            }
+        }
+        drop(member);
+
+        sleep(check.interval).await;
+    }
+}
+
+impl Prober {
+    async fn probe(&mut self, member: &Member, check: &HealthCheckConfig) -> Result<(), String> {
+        if check.protocol == HealthCheckProtocol::Tcp {
+            return self
+                .connector
+                .call(member.url.clone())
+                .await
+                .map(drop)
+                .map_err(|e| e.to_string());
+        }
+
+        let path = check.path.as_deref().unwrap_or("/");
+        let uri = format!(
+            "{}://{}{}",
+            member.url.scheme_str().unwrap_or("http"),
+            member.url.authority().expect("upstream urls are validated"),
+            path
+        );
+        let req = Request::get(uri)
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+        let mut res = self.client.request(req).await.map_err(|e| e.to_string())?;
+
+        let status = res.status();
+        let expected = match check.expect_status {
+            Some(expected) => status.as_u16() == expected,
+            None => status.is_success(),
+        };
+        if !expected {
+            return Err(format!("unexpected status {}", status));
+        }
+
+        let expect_body = match &check.expect_body {
+            Some(expect_body) => expect_body,
+            None => return Ok(()),
+        };
+        let mut body = Vec::new();
+        while body.len() < MAX_PROBE_BODY {
+            match res.body_mut().data().await {
+                Some(chunk) => body.extend_from_slice(&chunk.map_err(|e| e.to_string())?),
+                None => break,
+            }
+        }
+        if String::from_utf8_lossy(&body).contains(expect_body.as_str()) {
+            Ok(())
+        } else {
+            Err(format!("body doesn't contain `{}`", expect_body))
+        }
+    }
+}
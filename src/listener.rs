@@ -1,20 +1,60 @@
+use crate::client_auth::ClientCert;
+use crate::timeout::TimedConnection;
 use futures_util::Future;
 use hyper::server::accept::Accept;
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io;
-use std::net::SocketAddr;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::time::Sleep;
 
+/// The address of either end of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    /// A Unix socket, with its path unless it is unnamed, as client sockets
+    /// usually are.
+    Unix(Option<PathBuf>),
+}
+
+impl Address {
+    /// The IP address, for TCP connections.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::Tcp(addr) => Some(addr.ip()),
+            Address::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Address::Unix(None) => f.write_str("unix:<unnamed>"),
+        }
+    }
+}
+
 pub trait Listener {
     /// The connection type returned by this listener.
     type Connection: Connection;
 
     /// Return the actual address this listener bound to.
-    fn local_addr(&self) -> Option<SocketAddr>;
+    fn local_addr(&self) -> Option<Address>;
 
     /// Try to accept an incoming Connection if ready
     fn poll_accept(
@@ -26,8 +66,312 @@ pub trait Listener {
 /// A 'Connection' represents an open connection to a client
 pub trait Connection: AsyncRead + AsyncWrite {
     /// The remote address, i.e. the client's socket address.
-    fn remote_addr(&self) -> SocketAddr;
+    fn remote_addr(&self) -> Address;
     fn sni_hostname(&self) -> Option<&str>;
+    /// The certificate the client authenticated with, if it did.
+    fn client_cert(&self) -> Option<ClientCert>;
+}
+
+impl Listener for TcpListener {
+    type Connection = TcpConnection;
+
+    fn local_addr(&self) -> Option<Address> {
+        self.local_addr().ok().map(Address::Tcp)
+    }
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<Self::Connection>> {
+        (*self)
+            .poll_accept(cx)
+            .map_ok(|(stream, addr)| TcpConnection::new(stream, addr))
+    }
+}
+
+/// An accepted TCP connection with the client's address, which the socket
+/// can no longer tell once the client reset the connection.
+#[derive(Debug)]
+pub struct TcpConnection {
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+}
+
+impl TcpConnection {
+    pub fn new(stream: TcpStream, remote_addr: SocketAddr) -> TcpConnection {
+        TcpConnection {
+            stream,
+            remote_addr,
+        }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl Connection for TcpConnection {
+    fn remote_addr(&self) -> Address {
+        Address::Tcp(self.remote_addr)
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+
+    fn client_cert(&self) -> Option<ClientCert> {
+        None
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a socket left behind by a
+/// previous run.
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// The first file descriptor passed with `LISTEN_FDS`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets handed over by systemd socket activation. Listeners
+/// whose address matches one of them take it over instead of binding.
+#[derive(Debug, Default)]
+pub struct Inherited {
+    tcp: Vec<(SocketAddr, std::net::TcpListener)>,
+    unix: Vec<(PathBuf, std::os::unix::net::UnixListener)>,
+}
+
+impl Inherited {
+    /// Take the sockets described by `LISTEN_PID` and `LISTEN_FDS`, and
+    /// clear those variables so they aren't passed on to children. Call this
+    /// before any other thread is started, since it changes the environment.
+    pub fn from_env() -> Inherited {
+        let fds = listen_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let mut inherited = Inherited::default();
+        for fd in fds {
+            // Safety: the protocol hands these descriptors to us, and each is
+            // only taken once.
+            unsafe { inherited.take(fd) };
+        }
+        inherited
+    }
+
+    /// Adopt `fd` if it is a listening TCP or Unix socket, or close it.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open descriptor owned by nothing else.
+    unsafe fn take(&mut self, fd: RawFd) {
+        if !is_stream_listener(fd) {
+            warn!(
+                "ignoring inherited file descriptor {}, it isn't a listening stream socket",
+                fd
+            );
+            drop(OwnedFd::from_raw_fd(fd));
+            return;
+        }
+        // Keep the sockets from leaking into the processes we spawn.
+        if let Err(e) = set_cloexec(fd) {
+            warn!(
+                "could not set close-on-exec on file descriptor {}: {}",
+                fd, e
+            );
+        }
+        let tcp = std::net::TcpListener::from_raw_fd(fd);
+        if let Ok(address) = tcp.local_addr() {
+            self.tcp.push((address, tcp));
+            return;
+        }
+        let unix = std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd());
+        match unix
+            .local_addr()
+            .ok()
+            .and_then(|address| address.as_pathname().map(PathBuf::from))
+        {
+            Some(path) => self.unix.push((path, unix)),
+            None => warn!(
+                "ignoring inherited file descriptor {}, it isn't bound to an address",
+                fd
+            ),
+        }
+    }
+
+    /// The inherited socket bound to `address`, or a newly bound one.
+    pub fn tcp(&mut self, address: SocketAddr) -> io::Result<TcpListener> {
+        match self.tcp.iter().position(|(bound, _)| *bound == address) {
+            Some(index) => {
+                let (_, listener) = self.tcp.swap_remove(index);
+                info!("taking over the inherited socket for {}", address);
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            }
+            None => std::net::TcpListener::bind(address).and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            }),
+        }
+    }
+
+    /// The inherited socket bound to `path`, or a newly bound one.
+    pub fn unix(&mut self, path: &Path) -> io::Result<UnixListener> {
+        match self.unix.iter().position(|(bound, _)| bound == path) {
+            Some(index) => {
+                let (_, listener) = self.unix.swap_remove(index);
+                info!("taking over the inherited socket for {}", path.display());
+                listener.set_nonblocking(true)?;
+                UnixListener::from_std(listener)
+            }
+            None => bind_unix(path),
+        }
+    }
+
+    /// Close the inherited sockets no listener asked for.
+    pub fn close_unused(self) {
+        for (address, _) in &self.tcp {
+            warn!(
+                "closing inherited socket for {}, no listener uses it",
+                address
+            );
+        }
+        for (path, _) in &self.unix {
+            warn!(
+                "closing inherited socket for {}, no listener uses it",
+                path.display()
+            );
+        }
+    }
+}
+
+/// The descriptors passed to process `pid` according to the values of
+/// `LISTEN_PID` and `LISTEN_FDS`. Without `LISTEN_PID` the descriptors are
+/// assumed to be ours.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<RawFd> {
+    let none = LISTEN_FDS_START..LISTEN_FDS_START;
+    if listen_pid.is_some_and(|listen_pid| listen_pid.parse() != Ok(pid)) {
+        return none;
+    }
+    match listen_fds.and_then(|fds| fds.parse::<RawFd>().ok()) {
+        Some(count) if count > 0 => LISTEN_FDS_START..LISTEN_FDS_START + count,
+        _ => none,
+    }
+}
+
+/// Whether `fd` is a stream socket that is listening for connections, as
+/// opposed to a datagram socket, or a connected or merely bound one.
+fn is_stream_listener(fd: RawFd) -> bool {
+    let option = |name| {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // Safety: `value` and `len` describe a buffer the size of the option.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        Some(value).filter(|_| ret == 0)
+    };
+    option(libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+        && option(libc::SO_ACCEPTCONN).is_some_and(|listening| listening != 0)
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // Safety: neither call touches memory.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+
+    fn local_addr(&self) -> Option<Address> {
+        let addr = self.local_addr().ok()?;
+        Some(Address::Unix(addr.as_pathname().map(PathBuf::from)))
+    }
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<Self::Connection>> {
+        (*self).poll_accept(cx).map_ok(|(stream, _addr)| stream)
+    }
+}
+
+impl Connection for UnixStream {
+    fn remote_addr(&self) -> Address {
+        let path = self
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(PathBuf::from));
+        Address::Unix(path)
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+
+    fn client_cert(&self) -> Option<ClientCert> {
+        None
+    }
 }
 
 pin_project_lite::pin_project! {
@@ -38,6 +382,8 @@ pin_project_lite::pin_project! {
   #[must_use = "streams do nothing unless polled"]
   pub struct Incoming<L> {
       sleep_on_errors: Option<Duration>,
+      header_read_timeout: Duration,
+      idle_timeout: Duration,
       #[pin]
       pending_error_delay: Option<Sleep>,
       #[pin]
@@ -46,31 +392,33 @@ pin_project_lite::pin_project! {
 }
 
 impl<L: Listener> Incoming<L> {
-    /// Construct an `Incoming` from an existing `Listener`.
-    pub fn new(listener: L) -> Self {
+    /// Construct an `Incoming` from an existing `Listener`, closing
+    /// connections that exceed the given timeouts.
+    pub fn new(listener: L, header_read_timeout: Duration, idle_timeout: Duration) -> Self {
         Self {
             listener,
             sleep_on_errors: Some(Duration::from_millis(250)),
+            header_read_timeout,
+            idle_timeout,
             pending_error_delay: None,
         }
     }
 
-    /// Set whether to sleep on accept errors.
-    ///
-    /// A possible scenario is that the process has hit the max open files
-    /// allowed, and so trying to accept a new connection will fail with
-    /// `EMFILE`. In some cases, it's preferable to just wait for some time, if
-    /// the application will likely close some files (or connections), and try
-    /// to accept the connection again. If this option is `true`, the error
-    /// will be logged at the `error` level, since it is still a big deal,
-    /// and then the listener will sleep for 1 second.
-    ///
-    /// In other cases, hitting the max open files should be treat similarly
-    /// to being out-of-memory, and simply error (and shutdown). Setting
-    /// this option to `None` will allow that.
-    ///
-    /// Default is 1 second.
-
+    // Set whether to sleep on accept errors.
+    //
+    // A possible scenario is that the process has hit the max open files
+    // allowed, and so trying to accept a new connection will fail with
+    // `EMFILE`. In some cases, it's preferable to just wait for some time, if
+    // the application will likely close some files (or connections), and try
+    // to accept the connection again. If this option is `true`, the error
+    // will be logged at the `error` level, since it is still a big deal,
+    // and then the listener will sleep for 1 second.
+    //
+    // In other cases, hitting the max open files should be treat similarly
+    // to being out-of-memory, and simply error (and shutdown). Setting
+    // this option to `None` will allow that.
+    //
+    // Default is 1 second.
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<L::Connection>> {
         let mut me = self.project();
         let mut optimistic_retry = true;
@@ -124,22 +472,88 @@ impl<L: Listener> Incoming<L> {
     }
 }
 fn is_connection_error(e: &io::Error) -> bool {
-    match e.kind() {
+    matches!(
+        e.kind(),
         io::ErrorKind::ConnectionRefused
-        | io::ErrorKind::ConnectionAborted
-        | io::ErrorKind::ConnectionReset => true,
-        _ => false,
-    }
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
 }
 
 impl<L: Listener> Accept for Incoming<L> {
-    type Conn = L::Connection;
+    type Conn = TimedConnection<L::Connection>;
     type Error = io::Error;
 
     fn poll_accept(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<io::Result<Self::Conn>>> {
-        self.poll_next(cx).map(Some)
+        let (header_read, idle) = (self.header_read_timeout, self.idle_timeout);
+        self.poll_next(cx)
+            .map_ok(|conn| TimedConnection::new(conn, header_read, idle))
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{listen_fds, Address, Connection, Inherited, Listener};
+    use futures_util::future::poll_fn;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+    use std::pin::Pin;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn keeps_the_address_of_reset_connections() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let client_addr = client.local_addr().unwrap();
+        // Closing with a zero linger resets the connection.
+        client.set_linger(Some(Duration::from_secs(0))).unwrap();
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let connection = poll_fn(|cx| Pin::new(&mut listener).poll_accept(cx))
+            .await
+            .unwrap();
+        assert!(connection.get_ref().peer_addr().is_err());
+        assert_eq!(Address::Tcp(client_addr), connection.remote_addr());
+    }
+
+    #[test]
+    fn adopts_only_listening_stream_sockets() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connected = std::net::TcpStream::connect(address).unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut inherited = Inherited::default();
+        for fd in [
+            listener.into_raw_fd(),
+            connected.into_raw_fd(),
+            udp.into_raw_fd(),
+        ] {
+            // Clear close-on-exec, as it is on descriptors systemd passes.
+            assert_eq!(unsafe { libc::fcntl(fd, libc::F_SETFD, 0) }, 0);
+            unsafe { inherited.take(fd) };
+        }
+        assert_eq!(inherited.tcp.len(), 1);
+        assert!(inherited.unix.is_empty());
+        let (adopted, listener) = &inherited.tcp[0];
+        assert_eq!(*adopted, address);
+        let flags = unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+
+    #[test]
+    fn reads_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 3..5);
+        assert_eq!(listen_fds(None, Some("1"), 42), 3..4);
+        assert!(listen_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(listen_fds(Some("42"), None, 42).is_empty());
+        assert!(listen_fds(Some("42"), Some("-1"), 42).is_empty());
     }
 }
@@ -1,73 +1,224 @@
 use hyper::service::{make_service_fn, service_fn};
+mod acme;
+mod balancer;
+mod certs;
+mod client_auth;
+mod config;
+mod connector;
+mod error_pages;
 mod errors;
 mod follow_redirects;
+mod health;
 mod listener;
+mod metrics;
 mod proxy;
+mod reload;
+mod retry;
+mod router;
+mod shutdown;
+mod timeout;
 mod tls;
+mod upstream;
 mod uri;
+mod x509;
 
+use config::{BindAddress, Protocol};
 use errors::send_error_res;
-use hyper::{client, Body, Request};
-use hyper_rustls::HttpsConnectorBuilder;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::{Body, Request, StatusCode};
 use listener::{Connection, Incoming, Listener};
+use log::info;
+use proxy::HttpsRedirect;
+use reload::Reloadable;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use timeout::TimedConnection;
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
-type ClientType = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+type ClientType = hyper::Client<connector::Connector>;
 fn main() {
-    // Serve an echo service over HTTPS, with proper error handling.
-    if let Err(e) = run_server() {
-        println!("FAILED: {}", e);
-    }
+    pretty_env_logger::init();
+    // The environment is only safe to change before the runtime's threads
+    // start.
+    let inherited = listener::Inherited::from_env();
+    // Run the proxy until it is told to stop, and exit with its status.
+    let code = match run_server(inherited) {
+        Ok(exit) => exit.code(),
+        Err(e) => {
+            println!("FAILED: {}", e);
+            1
+        }
+    };
+    std::process::exit(code);
 }
 
 #[tokio::main]
-async fn run_server() -> Result<(), GenericError> {
-    pretty_env_logger::init();
+async fn run_server(mut inherited: listener::Inherited) -> Result<shutdown::Exit, GenericError> {
+    let config_path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "proxy.toml".to_string());
+    let config = config::Config::load(&config_path)?;
+    let router = router::Router::new(&config)?;
+    for pool in router.pools() {
+        health::spawn(pool);
+    }
+    let router = Arc::new(Reloadable::new(router));
+    let certificates = Arc::new(certs::Resolver::new(certs::Certificates::load(&config)?));
+    let challenges = Arc::new(acme::Challenges::default());
+    let acme = match &config.acme {
+        Some(acme) => Some(acme::Acme::new(
+            acme,
+            config.acme_domains(),
+            challenges.clone(),
+            certificates.clone(),
+        )?),
+        None => None,
+    };
 
-    let in_addr = format!("127.0.0.1:{}", 1337);
+    // Installed before binding, so a signal is never missed once clients
+    // can connect.
+    let signals = shutdown::Signals::new()?;
+    let (trigger, shutdown) = shutdown::channel();
 
-    // The closure inside `make_service_fn` is run for each connection,
-    // creating a 'service' to handle requests for that specific connection.
+    // Bind every listener up front so a bad address fails startup before
+    // anything is served. Each then gets a long-running future to accept and
+    // serve its clients.
+    let mut servers: Vec<BoxFuture<'_, Result<(), hyper::Error>>> =
+        Vec::with_capacity(config.listeners.len());
+    if let Some(metrics) = &config.metrics {
+        servers.push(metrics::serve(metrics.address, shutdown.clone().wait()).boxed());
+    }
+    for listener in &config.listeners {
+        let timeouts = (listener.header_read_timeout, listener.idle_timeout);
+        let redirect = listener.redirect_to_https.map(|status| HttpsRedirect {
+            status: StatusCode::from_u16(status).expect("validated by Config"),
+            port: listener.https_port,
+        });
+        match (&listener.address, listener.protocol) {
+            (BindAddress::Tcp(address), Protocol::Https) => {
+                let tls_listener = tls::bind_tls(
+                    inherited.tcp(*address)?,
+                    certificates.clone(),
+                    challenges.clone(),
+                    client_auth::ClientAuth::new(listener.client_auth.as_ref(), &config.hosts)?,
+                    listener.max_handshakes,
+                    listener.handshake_timeout,
+                );
+                let server = http_server(
+                    tls_listener,
+                    router.clone(),
+                    challenges.clone(),
+                    None,
+                    timeouts,
+                    shutdown.clone(),
+                );
+                servers.push(server.boxed());
+            }
+            (BindAddress::Tcp(address), Protocol::Http) => {
+                let tcp_listener = inherited.tcp(*address)?;
+                let server = http_server(
+                    tcp_listener,
+                    router.clone(),
+                    challenges.clone(),
+                    redirect,
+                    timeouts,
+                    shutdown.clone(),
+                );
+                servers.push(server.boxed());
+            }
+            (BindAddress::Unix(path), _) => {
+                let unix_listener = inherited.unix(path)?;
+                let server = http_server(
+                    unix_listener,
+                    router.clone(),
+                    challenges.clone(),
+                    redirect,
+                    timeouts,
+                    shutdown.clone(),
+                );
+                servers.push(server.boxed());
+            }
+        }
+    }
+
+    inherited.close_unused();
+    // Started once the listeners answering its challenges are bound.
+    if let Some(acme) = acme {
+        tokio::spawn(acme.run());
+    }
 
-    // Create a TCP listener via tokio.
-    let mut resolver = rustls::ResolvesServerCertUsingSNI::new();
-    tls::add_certificate_to_resolver("localhost", &mut resolver);
-    let listener = tls::bind_tls(in_addr.parse().unwrap(), resolver).await?;
+    // Listening for SIGHUP replaces its default action of killing us.
+    let drain_timeout = config.drain_timeout;
+    tokio::spawn(
+        reload::Reloader {
+            path: config_path,
+            config,
+            router,
+            certificates,
+        }
+        .run(),
+    );
 
-    // Prepare a long-running future stream to accept and serve clients.
-    Ok(http_server(listener).await?)
+    let servers = futures::future::try_join_all(servers).map(|result| result.map(drop));
+    let exit = shutdown::serve(servers, signals.into_stream(), trigger, drain_timeout).await?;
+    Ok(exit)
 }
 
-async fn http_server<L>(listener: L) -> Result<(), hyper::Error>
+async fn http_server<L>(
+    listener: L,
+    router: Arc<Reloadable<router::Router>>,
+    challenges: Arc<acme::Challenges>,
+    redirect: Option<HttpsRedirect>,
+    (header_read_timeout, idle_timeout): (Duration, Duration),
+    shutdown: shutdown::Shutdown,
+) -> Result<(), hyper::Error>
 where
     L: Listener + Send,
     <L as Listener>::Connection: Send + Unpin + 'static,
 {
-    let https = HttpsConnectorBuilder::new()
-        .with_native_roots()
-        .https_or_http()
-        .enable_http1()
-        .enable_http2()
-        .build();
-
-    let client: client::Client<_, hyper::Body> =
-        hyper::Client::builder().set_host(true).build(https);
+    if let Some(addr) = listener.local_addr() {
+        info!("listening on {}", addr);
+    }
 
-    let service = make_service_fn(move |s: &L::Connection| {
-        let client = client.clone();
-        let ip = s.remote_addr();
+    let service = make_service_fn(move |s: &TimedConnection<L::Connection>| {
+        let router = router.clone();
+        let challenges = challenges.clone();
+        let remote_addr = s.remote_addr();
+        let activity = s.activity().clone();
 
-        let sni_hostname = s.sni_hostname().map(|name| name.to_string()).unwrap();
+        let sni_hostname = s.sni_hostname().map(|name| name.to_string());
+        let client_cert = s.client_cert();
 
         async move {
             Ok::<_, GenericError>(service_fn(move |req: Request<Body>| {
                 let sni_hostname = sni_hostname.clone();
-                proxy::handle(req, ip, client.to_owned(), sni_hostname)
+                let client_cert = client_cert.clone();
+                // Every request runs to completion on the config it started on.
+                let router = router.load();
+                let remote_addr = remote_addr.clone();
+                let active = activity.start();
+                let challenge = challenges.http_response(&req);
+                async move {
+                    if let Some(res) = challenge {
+                        return Ok(res);
+                    }
+                    let res = match redirect {
+                        Some(redirect) => proxy::redirect_to_https(req, redirect).await,
+                        None => {
+                            proxy::handle(req, remote_addr, sni_hostname, client_cert, router).await
+                        }
+                    };
+                    drop(active);
+                    res
+                }
             }))
         }
     });
-    let server = hyper::Server::builder(Incoming::new(listener))
+    let incoming = Incoming::new(listener, header_read_timeout, idle_timeout);
+    let server = hyper::Server::builder(incoming)
         .http1_preserve_header_case(false)
-        .serve(service);
+        .serve(service)
+        .with_graceful_shutdown(shutdown.wait());
     server.await
 }
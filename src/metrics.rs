@@ -0,0 +1,91 @@
+use crate::timeout::Timeout;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use log::info;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// Timeouts hit since startup, indexed like `Timeout::ALL`.
+static TIMEOUTS: [AtomicU64; Timeout::ALL.len()] = [ZERO; Timeout::ALL.len()];
+
+/// Held by tests that check the counters or time out on purpose, since all
+/// tests share the counters.
+#[cfg(test)]
+pub static COUNTERS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Count a timeout of kind `timeout`.
+pub fn timed_out(timeout: Timeout) {
+    TIMEOUTS[timeout as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The counters in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP proxy_timeouts_total Requests and connections that timed out.\n");
+    out.push_str("# TYPE proxy_timeouts_total counter\n");
+    for timeout in Timeout::ALL {
+        let _ = writeln!(
+            out,
+            "proxy_timeouts_total{{kind=\"{}\"}} {}",
+            timeout.name(),
+            TIMEOUTS[timeout as usize].load(Ordering::Relaxed)
+        );
+    }
+    out
+}
+
+/// Serve the counters at `/metrics` on `address` until `shutdown` resolves.
+pub async fn serve(
+    address: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    let service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let res = if req.uri().path() == "/metrics" {
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(render()))
+            } else {
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+            };
+            res
+        }))
+    });
+    let server = Server::try_bind(&address)?
+        .serve(service)
+        .with_graceful_shutdown(shutdown);
+    info!("serving metrics on {}", address);
+    server.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, timed_out, COUNTERS};
+    use crate::timeout::Timeout;
+
+    /// The value of the timeout counter for `kind`, as rendered.
+    fn count(kind: &str) -> u64 {
+        let series = format!("proxy_timeouts_total{{kind=\"{}\"}} ", kind);
+        let line = render()
+            .lines()
+            .find_map(|line| line.strip_prefix(&series).map(str::to_string));
+        line.unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_timeouts_by_kind() {
+        let _counters = COUNTERS.lock().await;
+        let (connect, first_byte) = (count("connect"), count("first_byte"));
+        timed_out(Timeout::Connect);
+        assert_eq!(count("connect"), connect + 1);
+        assert_eq!(count("first_byte"), first_byte);
+    }
+}
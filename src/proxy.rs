@@ -1,9 +1,31 @@
-use crate::{follow_redirects::request, ClientType, GenericError,send_error_res};
-use http::uri::Port;
+use crate::balancer::RequestContext;
+use crate::client_auth::{
+    ClientCert, X_CLIENT_CERT_FINGERPRINT, X_CLIENT_CERT_SAN, X_CLIENT_CERT_SUBJECT,
+};
+use crate::error_pages::PageVars;
+use crate::errors::Error;
+use crate::follow_redirects::{buffer_body, content_length, request};
+use crate::listener::Address;
+use crate::router::{RouteMatch, Router};
+use crate::send_error_res;
+use crate::timeout::{self, Timeout};
+use http::request::Parts;
+use http::uri::{Authority, Port};
+use hyper::body::HttpBody;
 use hyper::{
-    header::{self, HeaderValue},
-    Body, Request, Response, Uri,
+    header::{self, HeaderMap, HeaderValue},
+    Body, Request, Response, StatusCode, Uri,
 };
+use log::{debug, log, Level};
+use rand::Rng;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::time::sleep;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Longest client supplied request ID passed on, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
 
 pub fn get_non_default_port(uri: &Uri) -> Option<Port<&str>> {
     match (uri.port().map(|p| p.as_u16()), is_schema_secure(uri)) {
@@ -19,68 +41,389 @@ fn is_schema_secure(uri: &Uri) -> bool {
         .unwrap_or_default()
 }
 
-//<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>> hyper::Client<hyper::client::HttpConnector>,
 pub async fn proxy(
-    mut req: Request<Body>,
-    client: ClientType,
-) -> Result<Response<Body>, GenericError> {
-    let out_addr = "http://a";
-
-    let uri_string = format!(
-        "{}{}",
-        out_addr,
-        req.uri()
-            .path_and_query()
-            .map(|x| x.as_str())
-            .unwrap_or("/")
-    )
-    .to_owned();
-
-    *req.version_mut() = hyper::Version::HTTP_11;
-    *req.uri_mut() = uri_string.parse()?;
-
-    let uri = req.uri().clone();
-
-    req.headers_mut().insert(header::HOST, {
-        let hostname = uri.host().expect("authority implies host");
-        if let Some(port) = get_non_default_port(&uri) {
-            let s = format!("{}:{}", hostname, port);
-            HeaderValue::from_str(&s)
+    req: Request<Body>,
+    route: &RouteMatch<'_>,
+    client_ip: Option<IpAddr>,
+) -> Result<Response<Body>, Error> {
+    let options = &route.route.options;
+    let pool = &route.route.upstream;
+    options.retries.start_request();
+
+    let (parts, body) = req.into_parts();
+    let body = timeout::client_body(body, options.timeouts.client_body);
+    // Only bodies that are known to fit in memory are buffered for retries,
+    // anything else is streamed and tried once.
+    let retry = options.retries.allows(&parts.method)
+        && (body.is_end_stream()
+            || content_length(&parts.headers)
+                .is_some_and(|len| len <= options.max_buffered_body as u64));
+    let (mut body, replay) = if retry {
+        let bytes = buffer_body(body, &parts.headers, options.max_buffered_body).await?;
+        (None, Some(bytes))
+    } else {
+        (Some(body), None)
+    };
+
+    let mut tried = Vec::new();
+    loop {
+        let ctx = RequestContext {
+            client_ip,
+            headers: &parts.headers,
+            tried: &tried,
+        };
+        // Retry on a member that was already tried rather than not at all.
+        let member = route.route.balancer.pick(pool, &ctx).or_else(|| {
+            let ctx = RequestContext { tried: &[], ..ctx };
+            route.route.balancer.pick(pool, &ctx)
+        });
+        // Counted as outstanding until the upstream's response headers arrive.
+        let in_flight = member
+            .ok_or_else(|| Error::NoHealthyUpstream(pool.name.clone()))?
+            .start_request();
+
+        let body = body
+            .take()
+            .unwrap_or_else(|| Body::from(replay.clone().unwrap_or_default()));
+        let req = upstream_request(&parts, &in_flight.member().url, &route.path_and_query, body)?;
+        let result = request(req, options).await;
+        // Errors reading the client's body say nothing about the upstream.
+        match &result {
+            Ok(res) => pool.report(in_flight.member(), !res.status().is_server_error()),
+            Err(e) if e.is_upstream() => pool.report(in_flight.member(), false),
+            Err(_) => {}
+        }
+
+        let backoff = if retry {
+            options.retries.retry(tried.len() as u32, &result)
         } else {
-            HeaderValue::from_str(hostname)
+            None
+        };
+        match (backoff, result) {
+            (Some(backoff), _) => {
+                debug!(
+                    "retrying request to upstream `{}` server {} in {:?}",
+                    pool.name,
+                    in_flight.member().url,
+                    backoff
+                );
+                tried.push(in_flight.member().clone());
+                drop(in_flight);
+                sleep(backoff).await;
+            }
+            (None, result) => return result,
         }
-        .expect("uri host is valid header value")
-    });
+    }
+}
 
-    // let mut forward_res = client.request(req).await.unwrap_or_else(move |err| {
-    //     send_error_res(err.to_string(), http::StatusCode::BAD_GATEWAY).unwrap()
-    // });
+/// The request to send to an upstream server at `base` for the client's
+/// request `parts`.
+fn upstream_request(
+    parts: &Parts,
+    base: &Uri,
+    path_and_query: &str,
+    body: Body,
+) -> Result<Request<Body>, Error> {
+    let out_addr = format!(
+        "{}://{}",
+        base.scheme_str().unwrap_or("http"),
+        base.authority().expect("upstream urls are validated")
+    );
+    let uri: Uri = format!("{}{}", out_addr, path_and_query).parse()?;
+
+    let mut req = Request::builder()
+        .method(parts.method.clone())
+        .uri(uri.clone())
+        .version(hyper::Version::HTTP_11)
+        .body(body)?;
+    req.headers_mut().clone_from(&parts.headers);
+
+    // Unix socket upstreams have no name of their own, so they get the
+    // client's `Host` header.
+    if uri.scheme_str() != Some("unix") {
+        req.headers_mut().insert(header::HOST, {
+            let hostname = uri.host().expect("authority implies host");
+            if let Some(port) = get_non_default_port(&uri) {
+                let s = format!("{}:{}", hostname, port);
+                HeaderValue::from_str(&s)
+            } else {
+                HeaderValue::from_str(hostname)
+            }
+            .expect("uri host is valid header value")
+        });
+    }
 
-    Ok(request(&mut req, client).await)
+    Ok(req)
 }
 
 pub async fn handle(
     req: Request<Body>,
-    ip: std::net::SocketAddr,
-    client: hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
-    sni_hostname: String,
+    remote_addr: Address,
+    sni_hostname: Option<String>,
+    client_cert: Option<ClientCert>,
+    router: Arc<Router>,
 ) -> Result<Response<Body>, http::Error> {
-    if None == req.headers().get("host") && None == req.uri().authority() {
-        return send_error_res(http::StatusCode::BAD_REQUEST);
-    }
     let (mut parts, body) = req.into_parts();
-    if parts.uri.authority().is_some() {
-        parts.headers.insert(
-            "host",
-            http::HeaderValue::from_str(&format!("{}", parts.uri.authority().unwrap())).unwrap(),
-        );
+    let request_id = request_id(&parts.headers);
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        parts.headers.insert(X_REQUEST_ID, value);
     }
+    let accept = parts.headers.get(header::ACCEPT).cloned();
+    let method = parts.method.clone();
+    let path = parts.uri.path().to_string();
+    let host = match parts.uri.authority() {
+        Some(authority) => authority.to_string(),
+        None => parts
+            .headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("-")
+            .to_string(),
+    };
 
-    parts.headers.insert(
-        "x-forwarded-for",
-        http::HeaderValue::from_str(&format!("{}", ip)).unwrap(),
-    );
-    Ok(proxy(Request::from_parts(parts, body), client.to_owned())
+    // Errors before a route is picked get the global error pages.
+    let (result, pages, upstream) = match route_request(
+        &mut parts,
+        &remote_addr,
+        sni_hostname.as_deref(),
+        client_cert.as_ref(),
+        &router,
+    ) {
+        Ok(route) => {
+            let req = Request::from_parts(parts, body);
+            let result = match route.route.options.timeouts.total {
+                Some(total) => tokio::time::timeout(total, proxy(req, &route, remote_addr.ip()))
+                    .await
+                    .unwrap_or_else(|_| Err(Error::timeout(Timeout::Total))),
+                None => proxy(req, &route, remote_addr.ip()).await,
+            };
+            let route = route.route;
+            (
+                result,
+                &route.options.error_pages,
+                Some(route.upstream.name.as_str()),
+            )
+        }
+        Err(e) => (Err(e), router.error_pages(), None),
+    };
+
+    match result {
+        Ok(res) if pages.intercepts(&res) => Ok(pages.render(
+            accept.as_ref(),
+            &PageVars {
+                status: res.status(),
+                error: None,
+                request_id: &request_id,
+                upstream,
+            },
+        )),
+        Ok(res) => Ok(res),
+        Err(e) => {
+            let level = if e.status() == StatusCode::INTERNAL_SERVER_ERROR {
+                Level::Error
+            } else if e.status().is_server_error() {
+                Level::Warn
+            } else {
+                Level::Debug
+            };
+            log!(
+                level,
+                "request failed: error={} status={} client={} method={} host={} path={} request_id={} reason=\"{}\"",
+                e.code(),
+                e.status().as_u16(),
+                remote_addr,
+                method,
+                host,
+                path,
+                request_id,
+                e
+            );
+            Ok(pages.render(
+                accept.as_ref(),
+                &PageVars {
+                    status: e.status(),
+                    error: Some(&e),
+                    request_id: &request_id,
+                    upstream,
+                },
+            ))
+        }
+    }
+}
+
+/// The client's request ID if it sent a usable one, otherwise a new random
+/// one.
+fn request_id(headers: &HeaderMap) -> String {
+    let id = headers
+        .get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN);
+    match id {
+        Some(id) => id.to_string(),
+        None => {
+            let bytes: [u8; 16] = rand::thread_rng().gen();
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+    }
+}
+
+/// Pick the route for a request and fill in the headers sent upstream.
+fn route_request<'r>(
+    parts: &mut Parts,
+    remote_addr: &Address,
+    sni_hostname: Option<&str>,
+    client_cert: Option<&ClientCert>,
+    router: &'r Router,
+) -> Result<RouteMatch<'r>, Error> {
+    if let Some(authority) = parts.uri.authority() {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            parts.headers.insert(header::HOST, host);
+        }
+    }
+    if !parts.headers.contains_key(header::HOST) {
+        return Err(Error::MissingHost);
+    }
+
+    let host = parts
+        .headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let path_and_query = parts.uri.path_and_query().map_or("/", |x| x.as_str());
+    let route = router.route(
+        sni_hostname,
+        host.as_ref().map(Authority::host),
+        path_and_query,
+    )?;
+    if let Some(rule) = &route.route.client_cert {
+        if !rule.allows(client_cert) {
+            return Err(Error::ClientCertRejected);
+        }
+    }
+
+    if let Some(ip) = remote_addr.ip() {
+        if let Ok(ip) = HeaderValue::from_str(&ip.to_string()) {
+            parts.headers.insert("x-forwarded-for", ip);
+        }
+    }
+    for name in [
+        X_CLIENT_CERT_SUBJECT,
+        X_CLIENT_CERT_SAN,
+        X_CLIENT_CERT_FINGERPRINT,
+    ] {
+        parts.headers.remove(name);
+    }
+    if let Some(cert) = client_cert {
+        let values = [
+            (X_CLIENT_CERT_SUBJECT, cert.subject.clone()),
+            (X_CLIENT_CERT_SAN, cert.sans.join(", ")),
+            (X_CLIENT_CERT_FINGERPRINT, cert.fingerprint.clone()),
+        ];
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                if !value.is_empty() {
+                    parts.headers.insert(name, value);
+                }
+            }
+        }
+    }
+    Ok(route)
+}
+
+/// Where a plain HTTP listener sends clients instead of proxying them.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsRedirect {
+    pub status: StatusCode,
+    pub port: Option<u16>,
+}
+
+/// Answer a plain HTTP request with a redirect to its `https://` equivalent.
+pub async fn redirect_to_https(
+    req: Request<Body>,
+    redirect: HttpsRedirect,
+) -> Result<Response<Body>, http::Error> {
+    let host = req.uri().authority().cloned().or_else(|| {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+    });
+    let host = match host {
+        Some(host) => host,
+        None => return send_error_res(http::StatusCode::BAD_REQUEST),
+    };
+
+    let path_and_query = req.uri().path_and_query().map_or("/", |x| x.as_str());
+    let location = match redirect.port {
+        Some(443) | None => format!("https://{}{}", host.host(), path_and_query),
+        Some(port) => format!("https://{}:{}{}", host.host(), port, path_and_query),
+    };
+
+    Response::builder()
+        .status(redirect.status)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redirect_to_https, HttpsRedirect};
+    use hyper::{header, Body, Request, StatusCode};
+
+    async fn redirect(
+        status: StatusCode,
+        port: Option<u16>,
+        host: Option<&str>,
+        uri: &str,
+    ) -> (StatusCode, Option<String>) {
+        let mut req = Request::builder().uri(uri);
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        let res = redirect_to_https(
+            req.body(Body::empty()).unwrap(),
+            HttpsRedirect { status, port },
+        )
         .await
-        .unwrap())
+        .unwrap();
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .map(|location| location.to_str().unwrap().to_string());
+        (res.status(), location)
+    }
+
+    #[tokio::test]
+    async fn redirects_to_https() {
+        let moved = StatusCode::MOVED_PERMANENTLY;
+        let permanent = StatusCode::PERMANENT_REDIRECT;
+        let location = |status, location: &str| (status, Some(location.to_string()));
+
+        assert_eq!(
+            location(moved, "https://example.com/a/b?c=d"),
+            redirect(moved, None, Some("example.com"), "/a/b?c=d").await
+        );
+        assert_eq!(
+            location(permanent, "https://example.com/"),
+            redirect(permanent, Some(443), Some("example.com"), "/").await
+        );
+        // The client's port is the plain HTTP one, so it is never kept.
+        assert_eq!(
+            location(moved, "https://example.com:8443/x?y"),
+            redirect(moved, Some(8443), Some("example.com:80"), "/x?y").await
+        );
+        assert_eq!(
+            location(moved, "https://example.com/"),
+            redirect(moved, None, Some("example.com:8080"), "/").await
+        );
+        // An absolute request uri wins over the `Host` header.
+        assert_eq!(
+            location(moved, "https://example.org/p"),
+            redirect(moved, None, Some("example.com"), "http://example.org/p").await
+        );
+        assert_eq!(
+            (StatusCode::BAD_REQUEST, None),
+            redirect(moved, None, None, "/").await
+        );
+    }
 }
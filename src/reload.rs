@@ -0,0 +1,180 @@
+use crate::certs::{Certificates, Resolver, Watch};
+use crate::config::Config;
+use crate::errors::Error;
+use crate::health;
+use crate::router::Router;
+use log::{error, info, warn};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often the certificate directories are checked for changes.
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A value that is replaced as a whole on reload. Readers hold on to the
+/// `Arc` they loaded, so whatever they started finishes on the old value.
+#[derive(Debug)]
+pub struct Reloadable<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Reloadable<T> {
+        Reloadable {
+            current: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// The parts of the running proxy that a reload replaces.
+pub struct Reloader {
+    pub path: String,
+    pub config: Config,
+    pub router: Arc<Reloadable<Router>>,
+    pub certificates: Arc<Resolver>,
+}
+
+impl Reloader {
+    /// Reload the config on every SIGHUP, and certificates whenever their
+    /// directories change, until the process exits.
+    pub async fn run(mut self) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!("can't listen for SIGHUP, reloading is disabled: {}", e);
+                return;
+            }
+        };
+        let mut watch = Watch::new(&self.config);
+        let mut checks = tokio::time::interval(CERTIFICATE_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                hangup = hangups.recv() => {
+                    if hangup.is_none() {
+                        return;
+                    }
+                    info!("received SIGHUP, reloading `{}`", self.path);
+                    match self.reload() {
+                        Ok(()) => {
+                            watch = Watch::new(&self.config);
+                            info!("reloaded `{}`", self.path);
+                        }
+                        Err(e) => error!("keeping the previous config, reload failed: {}", e),
+                    }
+                }
+                _ = checks.tick() => watch.check(&self.config, &self.certificates),
+            }
+        }
+    }
+
+    /// Build everything from the config file before swapping anything in,
+    /// so a bad config leaves the running one untouched.
+    fn reload(&mut self) -> Result<(), Error> {
+        let config = Config::load(&self.path)?;
+        let router = Router::new(&config)?;
+        let certificates = Certificates::load(&config)?;
+
+        if config.listeners != self.config.listeners
+            || config.client_auth_hosts() != self.config.client_auth_hosts()
+        {
+            warn!("listener and host `client_auth` changes only take effect after a restart");
+        }
+        if config.metrics != self.config.metrics
+            || config.drain_timeout != self.config.drain_timeout
+            || config.acme_domains() != self.config.acme_domains()
+            || config.acme != self.config.acme
+        {
+            warn!("`metrics`, `drain_timeout` and `acme` changes only take effect after a restart");
+        }
+
+        // The old pools stop being probed once the last request using them
+        // is done.
+        for pool in router.pools() {
+            health::spawn(pool);
+        }
+        self.router.store(router);
+        self.certificates.replace(certificates);
+        self.config = config;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reloadable, Reloader};
+    use crate::certs::{Certificates, Resolver};
+    use crate::config::Config;
+    use crate::router::Router;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// A config sending everything to `upstream`.
+    fn config_file(dir: &Path, upstream: &str) -> String {
+        format!(
+            r#"
+            certificate_dir = "{}"
+
+            [[listeners]]
+            address = "127.0.0.1:1337"
+
+            [upstreams.{upstream}]
+            url = "http://{upstream}"
+
+            [[hosts]]
+            upstream = "{upstream}"
+            default = true
+            "#,
+            dir.display(),
+            upstream = upstream
+        )
+    }
+
+    fn upstream(router: &Router) -> String {
+        let route = router.route(None, Some("example.com"), "/").unwrap();
+        route.route.upstream.name.clone()
+    }
+
+    #[test]
+    fn keeps_the_running_config_until_a_reload_succeeds() {
+        let dir = std::env::temp_dir().join(format!("proxy-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.toml");
+        fs::write(&path, config_file(&dir, "old")).unwrap();
+
+        let config = Config::load(path.to_str().unwrap()).unwrap();
+        let router = Arc::new(Reloadable::new(Router::new(&config).unwrap()));
+        let certificates = Arc::new(Resolver::new(Certificates::load(&config).unwrap()));
+        let mut reloader = Reloader {
+            path: path.to_string_lossy().into_owned(),
+            config,
+            router: router.clone(),
+            certificates,
+        };
+
+        // A request holds on to the router it started with.
+        let started = router.load();
+        fs::write(
+            &path,
+            config_file(&dir, "new").replace("[[hosts]]", "[[hosts]]\nbroken = 1"),
+        )
+        .unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!("old", upstream(&router.load()));
+
+        fs::write(&path, config_file(&dir, "new")).unwrap();
+        reloader.reload().unwrap();
+        assert_eq!("new", upstream(&router.load()));
+        assert_eq!("old", upstream(&started));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
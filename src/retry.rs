@@ -0,0 +1,132 @@
+use crate::config::{RetryBudgetConfig, RetryConfig, RetryOn};
+use crate::errors::Error;
+use crate::timeout::Timeout;
+use hyper::{Body, Method, Response};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Retries left to all routes together. Every request adds a fraction of a
+/// retry, up to a burst, and every retry takes a whole one, so a failing
+/// upstream sees at most that fraction of extra traffic.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    burst: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryBudgetConfig) -> RetryBudget {
+        let burst = f64::from(config.burst);
+        RetryBudget {
+            ratio: config.ratio,
+            burst,
+            tokens: Mutex::new(burst),
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.ratio).min(self.burst);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// When a route retries failed requests, on another server if there is one.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    config: RetryConfig,
+    budget: Arc<RetryBudget>,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig, budget: Arc<RetryBudget>) -> RetryPolicy {
+        RetryPolicy {
+            config: config.clone(),
+            budget,
+        }
+    }
+
+    /// Whether requests with `method` may be retried at all.
+    pub fn allows(&self, method: &Method) -> bool {
+        self.config.attempts > 0 && (self.config.non_idempotent || method.is_idempotent())
+    }
+
+    /// Count a request towards the retry budget.
+    pub fn start_request(&self) {
+        self.budget.deposit();
+    }
+
+    /// How long to wait before retrying after the `retries`th failed attempt
+    /// ended in `result`, or `None` to give up.
+    pub fn retry(&self, retries: u32, result: &Result<Response<Body>, Error>) -> Option<Duration> {
+        let retryable = match result {
+            Ok(res) => self.config.retry_on_status.contains(&res.status().as_u16()),
+            Err(Error::UpstreamConnect(_)) | Err(Error::Timeout(Timeout::Connect)) => {
+                self.config.retry_on.contains(&RetryOn::ConnectFailure)
+            }
+            Err(Error::UpstreamReset(_)) => self.config.retry_on.contains(&RetryOn::Reset),
+            Err(_) => false,
+        };
+        if !retryable || retries >= self.config.attempts || !self.budget.withdraw() {
+            return None;
+        }
+        Some(self.backoff(retries))
+    }
+
+    /// Exponential back-off with full jitter.
+    fn backoff(&self, retries: u32) -> Duration {
+        let cap = self
+            .config
+            .backoff
+            .saturating_mul(1 << retries.min(16))
+            .min(self.config.max_backoff);
+        cap.mul_f64(rand::thread_rng().gen())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryBudget, RetryPolicy};
+    use crate::config::{RetryBudgetConfig, RetryConfig};
+    use hyper::{Body, Response, StatusCode};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn spends_the_budget() {
+        let budget = Arc::new(RetryBudget::new(&RetryBudgetConfig {
+            ratio: 0.5,
+            burst: 1,
+        }));
+        let config: RetryConfig =
+            toml::from_str("attempts = 3\nretry_on_status = [503]\nmax_backoff = \"1s\"").unwrap();
+        let policy = RetryPolicy::new(&config, budget);
+        let unavailable = || {
+            Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::empty())
+                .unwrap())
+        };
+
+        let backoff = policy.retry(0, &unavailable()).unwrap();
+        assert!(backoff <= Duration::from_millis(25));
+        assert!(policy.retry(1, &unavailable()).is_none());
+
+        policy.start_request();
+        policy.start_request();
+        assert!(policy.retry(1, &unavailable()).is_some());
+        assert!(policy.retry(3, &unavailable()).is_none());
+        assert!(policy.retry(0, &Ok(Response::new(Body::empty()))).is_none());
+    }
+}
@@ -0,0 +1,529 @@
+use crate::balancer::{self, Balancer};
+use crate::config::{ClientCertRule, Config, Defaults, RouteConfig};
+use crate::connector::Connector;
+use crate::error_pages::ErrorPages;
+use crate::errors::Error;
+use crate::follow_redirects::{RedirectPolicy, RequestOptions};
+use crate::retry::{RetryBudget, RetryPolicy};
+use crate::upstream::Pool;
+use crate::ClientType;
+use http::StatusCode;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A virtual host resolved from the config, with its routes in the order
+/// they are tried.
+#[derive(Debug)]
+pub struct VirtualHost {
+    routes: Vec<Route>,
+    /// Whether clients authenticate for the host's names, in which case
+    /// requests have to come over a handshake for one of them.
+    client_auth: bool,
+}
+
+/// A path route with its upstream already looked up.
+#[derive(Debug)]
+pub struct Route {
+    matcher: PathMatcher,
+    rewrite: Rewrite,
+    pub upstream: Arc<Pool>,
+    pub balancer: Box<dyn Balancer>,
+    pub options: RequestOptions,
+    pub client_cert: Option<ClientCertRule>,
+}
+
+#[derive(Debug)]
+enum PathMatcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+    /// The host level upstream, used when no other route matches.
+    Any,
+}
+
+#[derive(Debug)]
+enum Rewrite {
+    Keep,
+    StripPrefix,
+    Replace(String),
+}
+
+/// The route picked for a request, and the path to send upstream.
+#[derive(Debug)]
+pub struct RouteMatch<'a> {
+    pub route: &'a Route,
+    pub path_and_query: String,
+}
+
+/// Why a request could not be matched to a virtual host.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum RouteError {
+    /// No host claims the name and there is no default host.
+    #[error("no host or route matches the request")]
+    NotFound,
+    /// The TLS SNI name and the `Host` header belong to different hosts.
+    #[error("host doesn't match the TLS server name")]
+    Misdirected,
+}
+
+impl RouteError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RouteError::NotFound => StatusCode::NOT_FOUND,
+            RouteError::Misdirected => StatusCode::MISDIRECTED_REQUEST,
+        }
+    }
+}
+
+/// Routing table mapping SNI names and `Host` headers to virtual hosts.
+#[derive(Debug)]
+pub struct Router {
+    pools: Vec<Arc<Pool>>,
+    hosts: Vec<VirtualHost>,
+    by_name: HashMap<String, usize>,
+    default: Option<usize>,
+    /// Pages for errors before a route is picked.
+    error_pages: ErrorPages,
+}
+
+impl Router {
+    /// Build the routing table from an already validated config. Fails if
+    /// an error page can't be loaded.
+    pub fn new(config: &Config) -> Result<Router, Error> {
+        let error_pages = ErrorPages::load(&config.defaults.error_pages)?;
+        let budget = Arc::new(RetryBudget::new(&config.retry_budget));
+        // Routes with the same connect timeout share a client, and with it
+        // the pooled upstream connections, unless their upstream has its own
+        // TLS settings.
+        let mut clients = HashMap::new();
+        let mut options = |route, pool: &Pool| {
+            request_options(
+                &config.defaults,
+                route,
+                pool,
+                &budget,
+                &error_pages,
+                &mut clients,
+            )
+        };
+
+        // Routes to the same upstream share its pool, so balancers that look
+        // at outstanding requests see all of them.
+        let pools: HashMap<&String, Arc<Pool>> = config
+            .upstreams
+            .iter()
+            .map(|(name, upstream)| Ok((name, Arc::new(Pool::new(name, upstream)?))))
+            .collect::<Result<_, Error>>()?;
+
+        let mut hosts = Vec::with_capacity(config.hosts.len());
+        let mut by_name = HashMap::new();
+        let mut default = None;
+        for (index, host) in config.hosts.iter().enumerate() {
+            let upstream = |name: &String| pools[name].clone();
+
+            let mut routes = host
+                .routes
+                .iter()
+                .map(|route| {
+                    let upstream = upstream(&route.upstream);
+                    let options = options(Some(route), &upstream)?;
+                    Ok(Route::new(route, upstream, options, &config.defaults))
+                })
+                .collect::<Result<Vec<Route>, Error>>()?;
+            // Stable, so routes of the same priority keep their declaration order.
+            routes.sort_by_key(|route| route.matcher.priority());
+            if let Some(name) = &host.upstream {
+                let upstream = upstream(name);
+                routes.push(Route {
+                    matcher: PathMatcher::Any,
+                    rewrite: Rewrite::Keep,
+                    balancer: balancer::from_config(&config.defaults.balance, &upstream),
+                    options: options(None, &upstream)?,
+                    upstream,
+                    client_cert: None,
+                });
+            }
+
+            hosts.push(VirtualHost {
+                routes,
+                client_auth: host.client_auth.is_some(),
+            });
+            for name in &host.names {
+                by_name.insert(normalize(name), index);
+            }
+            if host.default {
+                default = Some(index);
+            }
+        }
+
+        Ok(Router {
+            pools: pools.into_values().collect(),
+            hosts,
+            by_name,
+            default,
+            error_pages,
+        })
+    }
+
+    /// The pages for errors that happen before a route is picked.
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    /// Every upstream pool routes can send requests to.
+    pub fn pools(&self) -> &[Arc<Pool>] {
+        &self.pools
+    }
+
+    /// Pick the route for a request. `host` is the `Host` header without its
+    /// port. When both names are present they must resolve to the same
+    /// virtual host, otherwise the client reused a connection for a host this
+    /// connection wasn't negotiated for. Hosts with client authentication
+    /// also need the SNI name to be one of theirs.
+    pub fn route(
+        &self,
+        sni: Option<&str>,
+        host: Option<&str>,
+        path_and_query: &str,
+    ) -> Result<RouteMatch<'_>, RouteError> {
+        self.lookup(sni, host)?
+            .route(path_and_query)
+            .ok_or(RouteError::NotFound)
+    }
+
+    fn lookup(&self, sni: Option<&str>, host: Option<&str>) -> Result<&VirtualHost, RouteError> {
+        let index = match (
+            sni.map(|sni| self.find(sni)),
+            host.map(|host| self.find(host)),
+        ) {
+            (Some(sni), Some(host)) if sni != host => return Err(RouteError::Misdirected),
+            (_, Some(index)) | (Some(index), None) => index,
+            (None, None) => self.default,
+        };
+
+        let index = index.ok_or(RouteError::NotFound)?;
+        let host = &self.hosts[index];
+        if host.client_auth && sni.and_then(|sni| self.by_name.get(&normalize(sni))) != Some(&index)
+        {
+            return Err(RouteError::Misdirected);
+        }
+        Ok(host)
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.by_name.get(&normalize(name)).copied().or(self.default)
+    }
+}
+
+impl VirtualHost {
+    fn route(&self, path_and_query: &str) -> Option<RouteMatch<'_>> {
+        let (path, query) = match path_and_query.find('?') {
+            Some(i) => path_and_query.split_at(i),
+            None => (path_and_query, ""),
+        };
+
+        self.routes.iter().find_map(|route| {
+            let path = route.rewrite(path)?;
+            Some(RouteMatch {
+                route,
+                path_and_query: append_query(&path, query),
+            })
+        })
+    }
+}
+
+/// Add the client's `query` (including its `?`) to a rewritten path, which
+/// may have a query of its own.
+fn append_query(path: &str, query: &str) -> String {
+    match query.strip_prefix('?') {
+        Some("") if path.contains('?') => path.to_string(),
+        Some(query) if path.contains('?') => format!("{}&{}", path, query),
+        _ => format!("{}{}", path, query),
+    }
+}
+
+impl Route {
+    fn new(
+        config: &RouteConfig,
+        upstream: Arc<Pool>,
+        options: RequestOptions,
+        defaults: &Defaults,
+    ) -> Route {
+        let matcher = match (&config.path, &config.path_prefix, &config.path_regex) {
+            (Some(path), _, _) => PathMatcher::Exact(path.clone()),
+            (_, Some(prefix), _) => PathMatcher::Prefix(prefix.clone()),
+            (_, _, Some(regex)) => PathMatcher::Regex(regex.clone()),
+            _ => unreachable!("validated by Config::check_route"),
+        };
+        let rewrite = match &config.rewrite {
+            Some(rewrite) => Rewrite::Replace(rewrite.clone()),
+            None if config.strip_prefix => Rewrite::StripPrefix,
+            None => Rewrite::Keep,
+        };
+
+        let balance = config.balance.as_ref().unwrap_or(&defaults.balance);
+
+        Route {
+            matcher,
+            rewrite,
+            balancer: balancer::from_config(balance, &upstream),
+            upstream,
+            options,
+            client_cert: config.client_cert.clone(),
+        }
+    }
+
+    /// Return the path to forward if this route matches `path`.
+    fn rewrite<'p>(&self, path: &'p str) -> Option<Cow<'p, str>> {
+        match (&self.matcher, &self.rewrite) {
+            (PathMatcher::Exact(exact), rewrite) => {
+                if path != exact {
+                    return None;
+                }
+                match rewrite {
+                    Rewrite::Replace(to) => Some(Cow::Owned(to.clone())),
+                    _ => Some(Cow::Borrowed(path)),
+                }
+            }
+            (PathMatcher::Prefix(prefix), rewrite) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                // `/api` matches `/api` and `/api/users`, but not `/apis`.
+                if !(prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')) {
+                    return None;
+                }
+                match rewrite {
+                    Rewrite::Keep => Some(Cow::Borrowed(path)),
+                    Rewrite::StripPrefix if rest.starts_with('/') => Some(Cow::Borrowed(rest)),
+                    Rewrite::StripPrefix => Some(Cow::Owned(format!("/{}", rest))),
+                    Rewrite::Replace(to) => Some(Cow::Owned(join_paths(to, rest))),
+                }
+            }
+            (PathMatcher::Regex(regex), rewrite) => {
+                if !regex.is_match(path) {
+                    return None;
+                }
+                match rewrite {
+                    Rewrite::Replace(to) => Some(regex.replace(path, to.as_str())),
+                    _ => Some(Cow::Borrowed(path)),
+                }
+            }
+            (PathMatcher::Any, _) => Some(Cow::Borrowed(path)),
+        }
+    }
+}
+
+impl PathMatcher {
+    fn priority(&self) -> (u8, std::cmp::Reverse<usize>) {
+        match self {
+            PathMatcher::Exact(_) => (0, std::cmp::Reverse(0)),
+            PathMatcher::Prefix(prefix) => (1, std::cmp::Reverse(prefix.len())),
+            PathMatcher::Regex(_) => (2, std::cmp::Reverse(0)),
+            PathMatcher::Any => (3, std::cmp::Reverse(0)),
+        }
+    }
+}
+
+/// Resolve the options of a route, falling back to the defaults for anything
+/// it doesn't set.
+fn request_options(
+    defaults: &Defaults,
+    route: Option<&RouteConfig>,
+    upstream: &Pool,
+    budget: &Arc<RetryBudget>,
+    error_pages: &ErrorPages,
+    clients: &mut HashMap<(Duration, Option<String>), ClientType>,
+) -> Result<RequestOptions, Error> {
+    let redirects = route
+        .and_then(|route| route.redirects.as_ref())
+        .unwrap_or(&defaults.redirects);
+    let retries = route
+        .and_then(|route| route.retries.as_ref())
+        .unwrap_or(&defaults.retries);
+    let timeouts = route
+        .and_then(|route| route.timeouts.as_ref())
+        .unwrap_or(&defaults.timeouts);
+    let tls = upstream.tls.as_ref().map(|_| upstream.name.clone());
+    let client = clients
+        .entry((timeouts.connect, tls))
+        .or_insert_with(|| {
+            let connector = Connector::new(Some(timeouts.connect), upstream.tls.clone());
+            hyper::Client::builder().build(connector)
+        })
+        .clone();
+    let error_pages = match route.and_then(|route| route.error_pages.as_ref()) {
+        Some(config) => ErrorPages::load(config)?,
+        None => error_pages.clone(),
+    };
+    Ok(RequestOptions {
+        redirects: RedirectPolicy::from_config(redirects),
+        max_redirects: route
+            .and_then(|route| route.max_redirects)
+            .unwrap_or(defaults.max_redirects),
+        max_buffered_body: route
+            .and_then(|route| route.max_buffered_body)
+            .unwrap_or(defaults.max_buffered_body),
+        retries: RetryPolicy::new(retries, budget.clone()),
+        error_pages,
+        timeouts: timeouts.clone(),
+        client,
+    })
+}
+
+fn join_paths(base: &str, rest: &str) -> String {
+    match (base.ends_with('/'), rest.starts_with('/')) {
+        (true, true) => format!("{}{}", base, &rest[1..]),
+        (false, false) if !rest.is_empty() => format!("{}/{}", base, rest),
+        _ => format!("{}{}", base, rest),
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RouteError, Router};
+    use crate::client_auth::ClientCert;
+
+    fn router(default: bool) -> Router {
+        let config = format!(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:1337"
+
+            [upstreams.a]
+            url = "http://a"
+
+            [upstreams.b]
+            url = "http://b"
+
+            [[hosts]]
+            names = ["a.example", "www.a.example"]
+            upstream = "a"
+
+            [[hosts.routes]]
+            path_prefix = "/api"
+            upstream = "b"
+            strip_prefix = true
+
+            [[hosts.routes]]
+            path_prefix = "/api/v2/"
+            upstream = "b"
+            rewrite = "/v2"
+
+            [[hosts.routes]]
+            path = "/api/health"
+            upstream = "a"
+
+            [[hosts.routes]]
+            path_regex = "^/users/(\\d+)$"
+            upstream = "b"
+            rewrite = "/user?id=$1"
+
+            [[hosts]]
+            names = ["b.example"]
+            upstream = "b"
+            default = {}
+
+            [[hosts]]
+            names = ["secure.example"]
+            upstream = "a"
+            client_auth = {{ ca = "ca.pem" }}
+
+            [[hosts.routes]]
+            path = "/admin"
+            upstream = "a"
+            client_cert = {{ subjects = ["CN=admin"], fingerprints = ["AB:CD"] }}
+            "#,
+            default
+        );
+        Router::new(&config.parse().unwrap()).unwrap()
+    }
+
+    fn upstream(
+        router: &Router,
+        sni: Option<&str>,
+        host: Option<&str>,
+    ) -> Result<String, RouteError> {
+        router
+            .route(sni, host, "/")
+            .map(|m| m.route.upstream.name.clone())
+    }
+
+    #[test]
+    fn routes_by_sni_and_host() {
+        let router = router(false);
+        let a = Ok("a".to_string());
+        let b = Ok("b".to_string());
+        assert_eq!(upstream(&router, Some("a.example"), Some("A.example.")), a);
+        assert_eq!(
+            upstream(&router, Some("a.example"), Some("www.a.example")),
+            a
+        );
+        assert_eq!(upstream(&router, None, Some("b.example")), b);
+        assert_eq!(upstream(&router, Some("b.example"), None), b);
+    }
+
+    #[test]
+    fn rejects_sni_host_mismatch() {
+        let router = router(false);
+        let err = upstream(&router, Some("a.example"), Some("b.example")).unwrap_err();
+        assert_eq!(err, RouteError::Misdirected);
+    }
+
+    #[test]
+    fn checks_client_auth_and_certificates() {
+        let router = router(false);
+        let secure = Some("secure.example");
+        assert_eq!(upstream(&router, secure, secure), Ok("a".to_string()));
+        assert_eq!(
+            upstream(&router, None, secure),
+            Err(RouteError::Misdirected)
+        );
+
+        let rule = router.route(secure, secure, "/admin").unwrap().route;
+        let rule = rule.client_cert.as_ref().unwrap();
+        let cert = |subject: &str, fingerprint: &str| ClientCert {
+            subject: subject.to_string(),
+            sans: vec!["DNS:client.example".to_string()],
+            fingerprint: fingerprint.to_string(),
+        };
+        assert!(rule.allows(Some(&cert("CN=admin", "00"))));
+        assert!(rule.allows(Some(&cert("CN=other", "abcd"))));
+        assert!(!rule.allows(Some(&cert("CN=other", "00"))));
+        assert!(!rule.allows(None));
+    }
+
+    #[test]
+    fn falls_back_to_default_host() {
+        assert_eq!(
+            upstream(&router(false), None, Some("c.example")),
+            Err(RouteError::NotFound)
+        );
+        assert_eq!(
+            upstream(&router(true), Some("c.example"), Some("d.example")),
+            Ok("b".to_string())
+        );
+    }
+
+    #[test]
+    fn matches_paths_by_priority() {
+        let router = router(false);
+        let route = |path| {
+            let m = router.route(None, Some("a.example"), path).unwrap();
+            format!("{}{}", m.route.upstream.name, m.path_and_query)
+        };
+        assert_eq!(route("/api/health"), "a/api/health");
+        assert_eq!(route("/api/users?page=2"), "b/users?page=2");
+        assert_eq!(route("/api"), "b/");
+        assert_eq!(route("/api/v2/users"), "b/v2/users");
+        assert_eq!(route("/apis"), "a/apis");
+        assert_eq!(route("/users/42"), "b/user?id=42");
+        assert_eq!(route("/users/42?page=2"), "b/user?id=42&page=2");
+        assert_eq!(route("/users/me"), "a/users/me");
+    }
+}
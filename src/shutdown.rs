@@ -0,0 +1,191 @@
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+
+/// How the proxy stopped, once it was asked to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// Every request in flight finished.
+    Drained,
+    /// Requests were still in flight at the drain deadline.
+    DrainTimeout,
+    /// A second signal cut the drain short.
+    Forced,
+}
+
+impl Exit {
+    /// The process exit code.
+    pub fn code(self) -> i32 {
+        match self {
+            Exit::Drained => 0,
+            Exit::DrainTimeout | Exit::Forced => 2,
+        }
+    }
+}
+
+/// Notifies servers that they should stop accepting connections.
+pub struct Trigger(watch::Sender<()>);
+
+/// Resolves once the servers are asked to stop, see [`channel`].
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<()>);
+
+pub fn channel() -> (Trigger, Shutdown) {
+    let (tx, rx) = watch::channel(());
+    (Trigger(tx), Shutdown(rx))
+}
+
+impl Shutdown {
+    pub async fn wait(mut self) {
+        // An error means the trigger is gone, which is as good as a signal.
+        let _ = self.0.changed().await;
+    }
+}
+
+/// SIGTERM and SIGINT, as sent by systemd, container runtimes and Ctrl-C.
+pub struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Signals> {
+        Ok(Signals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Wait for the next signal and return its name.
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+
+    /// The names of the signals, as they arrive.
+    pub fn into_stream(self) -> impl Stream<Item = &'static str> + Unpin {
+        Box::pin(futures::stream::unfold(self, |mut signals| async move {
+            let name = signals.recv().await;
+            Some((name, signals))
+        }))
+    }
+}
+
+/// Run `servers` until one of `signals` arrives, then pull `trigger` and
+/// give them `drain_timeout` to finish the requests in flight. Returns early
+/// with the servers' error if one fails.
+pub async fn serve<F, E, S>(
+    servers: F,
+    mut signals: S,
+    trigger: Trigger,
+    drain_timeout: Duration,
+) -> Result<Exit, E>
+where
+    F: Future<Output = Result<(), E>>,
+    S: Stream<Item = &'static str> + Unpin,
+{
+    let mut servers = Box::pin(servers);
+    let name = tokio::select! {
+        result = &mut servers => return result.map(|()| Exit::Drained),
+        Some(name) = signals.next() => name,
+    };
+    info!(
+        "received {}, draining connections for up to {:?}",
+        name, drain_timeout
+    );
+    // Servers stop accepting, close idle connections and finish the rest.
+    let _ = trigger.0.send(());
+
+    tokio::select! {
+        result = tokio::time::timeout(drain_timeout, &mut servers) => match result {
+            Ok(result) => {
+                info!("all connections drained");
+                result.map(|()| Exit::Drained)
+            }
+            Err(_) => {
+                warn!("drain deadline passed, closing the remaining connections");
+                Ok(Exit::DrainTimeout)
+            }
+        },
+        Some(name) = signals.next() => {
+            warn!("received {} while draining, closing all connections", name);
+            Ok(Exit::Forced)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, serve, Exit};
+    use futures::channel::mpsc;
+    use futures::FutureExt;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::task::JoinHandle;
+    use tokio::time::sleep;
+
+    /// Serve requests that take `delay` to answer, and send SIGTERM while
+    /// one is in flight. Returns how the drain ended, and the request, which
+    /// resolves to whether it got its response.
+    async fn drain(delay: Duration, drain_timeout: Duration) -> (Exit, JoinHandle<bool>) {
+        let make = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                sleep(delay).await;
+                Ok::<_, Infallible>(Response::new(Body::from("done")))
+            }))
+        });
+        let (trigger, shutdown) = channel();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+        let addr = server.local_addr();
+        let server = server.with_graceful_shutdown(shutdown.wait());
+        let (signal, signals) = mpsc::unbounded();
+        let serving = tokio::spawn(serve(server, signals, trigger, drain_timeout));
+
+        let request: JoinHandle<bool> = tokio::spawn(async move {
+            let uri = format!("http://{}/", addr).parse().unwrap();
+            match hyper::Client::new().get(uri).await {
+                Ok(res) => hyper::body::to_bytes(res.into_body()).await.is_ok(),
+                Err(_) => false,
+            }
+        });
+        sleep(Duration::from_millis(50)).await;
+        signal.unbounded_send("SIGTERM").unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert!(refuses(addr).await, "accepted a connection while draining");
+
+        let exit = serving.await.unwrap().unwrap();
+        drop(signal);
+        (exit, request)
+    }
+
+    async fn refuses(addr: SocketAddr) -> bool {
+        TcpStream::connect(addr).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn finishes_requests_in_flight() {
+        let (exit, request) = drain(Duration::from_millis(200), Duration::from_secs(5)).await;
+        assert_eq!(Exit::Drained, exit);
+        assert_eq!(0, exit.code());
+        assert!(request.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_the_drain_deadline() {
+        let (exit, request) = drain(Duration::from_secs(5), Duration::from_millis(200)).await;
+        assert_eq!(Exit::DrainTimeout, exit);
+        assert_eq!(2, exit.code());
+        // The process exits here, taking the request with it.
+        assert!(request.now_or_never().is_none());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::client_auth::ClientCert;
+use crate::listener::{Address, Connection};
+use crate::metrics;
+use futures::task::AtomicWaker;
+use hyper::body::HttpBody;
+use hyper::Body;
+use log::debug;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Sent to HTTP/1 clients that started a request but didn't finish its
+/// headers in time.
+const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+/// The start of every HTTP/2 connection.
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0";
+
+/// A stage of handling a request that took too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    Handshake,
+    HeaderRead,
+    Idle,
+    ClientBody,
+    Connect,
+    FirstByte,
+    Total,
+}
+
+impl Timeout {
+    pub const ALL: [Timeout; 7] = [
+        Timeout::Handshake,
+        Timeout::HeaderRead,
+        Timeout::Idle,
+        Timeout::ClientBody,
+        Timeout::Connect,
+        Timeout::FirstByte,
+        Timeout::Total,
+    ];
+
+    /// Name of the timeout in config and metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Timeout::Handshake => "handshake",
+            Timeout::HeaderRead => "header_read",
+            Timeout::Idle => "idle",
+            Timeout::ClientBody => "client_body",
+            Timeout::Connect => "connect",
+            Timeout::FirstByte => "first_byte",
+            Timeout::Total => "total",
+        }
+    }
+
+    /// Whether the client was too slow, rather than the upstream.
+    pub fn is_client(self) -> bool {
+        matches!(
+            self,
+            Timeout::Handshake | Timeout::HeaderRead | Timeout::Idle | Timeout::ClientBody
+        )
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Timeout::Handshake => "TLS handshake",
+            Timeout::HeaderRead => "reading the request headers",
+            Timeout::Idle => "idle connection",
+            Timeout::ClientBody => "reading the request body",
+            Timeout::Connect => "connecting to the upstream",
+            Timeout::FirstByte => "waiting for the upstream's response",
+            Timeout::Total => "request",
+        })
+    }
+}
+
+/// The error a request body wrapped by [`client_body`] fails with, so the
+/// stall can be told apart from the upstream timing out.
+#[derive(Debug)]
+pub struct ClientBodyTimedOut;
+
+impl fmt::Display for ClientBodyTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("client stopped sending the request body")
+    }
+}
+
+impl std::error::Error for ClientBodyTimedOut {}
+
+/// Fail `body` if the client pauses for longer than `limit` between chunks.
+/// Bodies that are already complete are returned as they are, so they keep
+/// their length.
+pub fn client_body(body: Body, limit: Duration) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    Body::wrap_stream(futures::stream::unfold(
+        Some(body),
+        move |body| async move {
+            let mut body = body?;
+            match tokio::time::timeout(limit, body.data()).await {
+                Ok(Some(Ok(chunk))) => Some((Ok(chunk), Some(body))),
+                Ok(Some(Err(e))) => Some((Err(crate::GenericError::from(e)), None)),
+                Ok(None) => None,
+                Err(_) => Some((Err(ClientBodyTimedOut.into()), None)),
+            }
+        },
+    ))
+}
+
+/// Requests in flight on a client connection, shared by the connection and
+/// the service answering them.
+#[derive(Debug, Default)]
+pub struct Activity {
+    requests: AtomicUsize,
+    started: AtomicU64,
+    waker: AtomicWaker,
+}
+
+impl Activity {
+    /// Mark a request as in flight until the guard is dropped.
+    pub fn start(self: &Arc<Self>) -> ActiveRequest {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        self.started.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(self.clone())
+    }
+}
+
+pub struct ActiveRequest(Arc<Activity>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        if self.0.requests.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Start the idle timer of a connection waiting on reads.
+            self.0.waker.wake();
+        }
+    }
+}
+
+/// A client connection that is closed when the client is idle for longer
+/// than `idle`, or takes longer than `header_read` to send the headers of a
+/// request it started.
+pub struct TimedConnection<C> {
+    conn: C,
+    activity: Arc<Activity>,
+    header_read: Duration,
+    idle: Duration,
+    timer: Pin<Box<Sleep>>,
+    last_activity: Instant,
+    last_write: Instant,
+    /// When the first bytes of a request arrived, for HTTP/1.
+    request_started: Option<Instant>,
+    /// The number of requests started when last polled.
+    started: u64,
+    http2: Option<bool>,
+    state: State,
+}
+
+enum State {
+    Open,
+    /// Writing the 408 response, this much of it written so far.
+    Rejecting(usize),
+    Closed,
+}
+
+impl<C> TimedConnection<C> {
+    pub fn new(conn: C, header_read: Duration, idle: Duration) -> TimedConnection<C> {
+        let now = Instant::now();
+        TimedConnection {
+            conn,
+            activity: Arc::default(),
+            header_read,
+            idle,
+            timer: Box::pin(sleep(idle)),
+            last_activity: now,
+            last_write: now,
+            request_started: None,
+            started: 0,
+            http2: None,
+            state: State::Open,
+        }
+    }
+
+    pub fn activity(&self) -> &Arc<Activity> {
+        &self.activity
+    }
+
+    /// Catch up with the requests handled since the last poll, returning
+    /// whether one is in flight. Time spent handling requests counts as
+    /// activity, and a started request has all its headers.
+    fn sync(&mut self) -> bool {
+        let busy = self.activity.requests.load(Ordering::SeqCst) > 0;
+        let started = self.activity.started.load(Ordering::SeqCst);
+        if busy || started != self.started {
+            self.started = started;
+            self.last_activity = Instant::now();
+            self.request_started = None;
+        }
+        busy
+    }
+
+    /// When the connection times out if nothing happens until then.
+    fn deadline(&mut self) -> Option<(Instant, Timeout)> {
+        if self.sync() {
+            return None;
+        }
+        match self.request_started {
+            Some(request_started) => {
+                Some((request_started + self.header_read, Timeout::HeaderRead))
+            }
+            None => Some((self.last_activity + self.idle, Timeout::Idle)),
+        }
+    }
+
+    fn on_read(&mut self, data: &[u8]) {
+        self.last_activity = Instant::now();
+        if self.http2.is_none() {
+            self.http2 = Some(data.starts_with(HTTP2_PREFACE));
+        }
+        if self.http2 == Some(false) && self.request_started.is_none() && !self.sync() {
+            self.request_started = Some(self.last_activity);
+        }
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> TimedConnection<C> {
+    fn poll_reject(&mut self, cx: &mut Context<'_>, written: usize) -> Poll<io::Result<()>> {
+        let mut written = written;
+        while written < REQUEST_TIMEOUT_RESPONSE.len() {
+            match Pin::new(&mut self.conn).poll_write(cx, &REQUEST_TIMEOUT_RESPONSE[written..]) {
+                Poll::Ready(Ok(0)) | Poll::Ready(Err(_)) => break,
+                Poll::Ready(Ok(n)) => written += n,
+                Poll::Pending => {
+                    self.state = State::Rejecting(written);
+                    return Poll::Pending;
+                }
+            }
+        }
+        self.state = State::Closed;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncRead for TimedConnection<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            State::Open => {}
+            State::Rejecting(written) => return this.poll_reject(cx, written),
+            // Reads as the client closing the connection, so hyper shuts it
+            // down cleanly.
+            State::Closed => return Poll::Ready(Ok(())),
+        }
+
+        this.activity.waker.register(cx.waker());
+        let filled = buf.filled().len();
+        if let Poll::Ready(result) = Pin::new(&mut this.conn).poll_read(cx, buf) {
+            if buf.filled().len() > filled {
+                this.on_read(&buf.filled()[filled..]);
+            }
+            return Poll::Ready(result);
+        }
+
+        let (deadline, timeout) = match this.deadline() {
+            Some(deadline) => deadline,
+            None => return Poll::Pending,
+        };
+        if this.timer.deadline() != deadline {
+            this.timer.as_mut().reset(deadline);
+        }
+        if this.timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        metrics::timed_out(timeout);
+        debug!("closing client connection: {} timed out", timeout);
+        // Only answer when no response is being written, or the 408 would
+        // end up in the middle of it.
+        let started = this.request_started;
+        if timeout == Timeout::HeaderRead
+            && started.is_some_and(|started| this.last_write <= started)
+        {
+            this.poll_reject(cx, 0)
+        } else {
+            this.state = State::Closed;
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TimedConnection<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.conn).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                this.last_activity = Instant::now();
+                this.last_write = this.last_activity;
+            }
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().conn).poll_shutdown(cx)
+    }
+}
+
+impl<C: Connection + Unpin> Connection for TimedConnection<C> {
+    fn remote_addr(&self) -> Address {
+        self.conn.remote_addr()
+    }
+
+    fn sni_hostname(&self) -> Option<&str> {
+        self.conn.sni_hostname()
+    }
+
+    fn client_cert(&self) -> Option<ClientCert> {
+        self.conn.client_cert()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimedConnection;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn rejects_slow_request_headers() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server =
+            TimedConnection::new(server, Duration::from_millis(50), Duration::from_secs(60));
+        let mut client = client;
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        let mut buf = [0; 64];
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1\r\n");
+        // The rest of the headers never come.
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+
+        let mut response = String::new();
+        drop(server);
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 "));
+    }
+}
@@ -1,71 +1,190 @@
-use crate::listener::{Connection, Listener};
-use rustls::internal::pemfile::{certs, pkcs8_private_keys};
-use rustls::sign::{RSASigningKey, SigningKey};
-use rustls::ResolvesServerCertUsingSNI;
-use std::fs::File;
+use crate::acme::{Challenges, ACME_TLS_ALPN};
+use crate::certs::Resolver;
+use crate::client_auth::{ClientAuth, ClientCert};
+use crate::errors::Error;
+use crate::listener::{Address, Connection, Listener, TcpConnection};
+use crate::metrics;
+use crate::x509;
+use rustls::sign::{any_ecdsa_type, any_supported_type, CertifiedKey, RSASigningKey, SigningKey};
+use rustls::{
+  Certificate, ClientCertVerifier, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert,
+  ServerConfig, Session, SignatureScheme,
+};
+use std::collections::HashMap;
+use std::fs;
 use std::io;
-use std::io::BufReader;
-use std::net::SocketAddr;
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{server::TlsStream, Accept, TlsAcceptor};
+use tokio::time::{sleep, timeout, Timeout};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 
-use futures_util::Future;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use log::{debug, warn};
 
 use std::sync::Arc;
 
-pub fn add_certificate_to_resolver(hostname: &str, resolver: &mut ResolvesServerCertUsingSNI) {
-  //let resolve = |filename| format!("./{filename}", filename = &filename);
-  //    config_dir = env::var("XDG_CONFIG_HOME").unwrap().to_string(),
+/// Certificates expiring within this long are warned about when loaded.
+const EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
-  let cert_file = &mut BufReader::new(File::open("cert.pem").unwrap());
-  let key_file = &mut BufReader::new(File::open("privkey.pem").unwrap());
+/// The schemes a key is signed with to check it against its certificate.
+const KEY_CHECK_SCHEMES: [SignatureScheme; 4] = [
+  SignatureScheme::RSA_PKCS1_SHA256,
+  SignatureScheme::ECDSA_NISTP256_SHA256,
+  SignatureScheme::ECDSA_NISTP384_SHA384,
+  SignatureScheme::ED25519,
+];
 
-  let cert_chain = certs(cert_file).unwrap();
-  let mut keys = pkcs8_private_keys(key_file).unwrap();
-  let signing_key = RSASigningKey::new(&keys.remove(0)).unwrap();
-  let signing_key_boxed: Arc<Box<dyn SigningKey>> = Arc::new(Box::new(signing_key));
+/// A fatal `unrecognized_name` alert record, which rustls has no way of
+/// sending when the resolver has no certificate.
+const UNRECOGNIZED_NAME_ALERT: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x70];
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: usize = 0x0000;
+const ALPN_EXTENSION: usize = 0x0010;
+const HOST_NAME: u8 = 0x00;
+/// How long to wait for the rest of a ClientHello that came in pieces.
+const CLIENT_HELLO_WAIT: Duration = Duration::from_millis(10);
 
-  resolver
-    .add(
-      hostname,
-      rustls::sign::CertifiedKey::new(cert_chain, signing_key_boxed),
-    )
-    .expect("Invalid certificate");
-}
+/// Load the certificate chain and private key served for `hostname`. The
+/// key may be PKCS#8, PKCS#1 (RSA) or SEC1 (ECDSA), and has to belong to
+/// the first certificate of the chain.
+pub fn load_certified_key(
+  hostname: &str,
+  cert_path: &Path,
+  key_path: &Path,
+) -> Result<CertifiedKey, Error> {
+  let cert_error = |reason: String| Error::Certificate {
+    domain: hostname.to_string(),
+    reason,
+  };
+  let read_pem = |path: &Path| {
+    let pem = fs::read_to_string(path)
+      .map_err(|e| cert_error(format!("could not read `{}`: {}", path.display(), e)))?;
+    x509::pem_blocks(&pem)
+      .map_err(|e| cert_error(format!("`{}` is not a valid PEM file: {}", path.display(), e)))
+  };
 
-/* pub fn init_certs(configs: Vec<config::ConfigItem>) {
-  let existing: Vec<config::ConfigItem> = configs
+  let cert_chain: Vec<Certificate> = read_pem(cert_path)?
     .into_iter()
-    .filter(|host| {
-      Path::new(&format!(
-        "{config_dir}/proxy/{domain}",
-        config_dir = env::var("XDG_CONFIG_HOME").unwrap().to_string(),
-        domain = &host.domain
-      ))
-      .exists()
-    })
+    .filter(|(label, _)| label == "CERTIFICATE")
+    .map(|(_, der)| Certificate(der))
     .collect();
-}V
- */
+  if cert_chain.is_empty() {
+    return Err(cert_error(format!(
+      "`{}` contains no certificates",
+      cert_path.display()
+    )));
+  }
 
-pub struct TlsListener {
-  listener: TcpListener,
-  acceptor: TlsAcceptor,
-  state: TlsListenerState,
+  let signing_key = read_pem(key_path)?
+    .into_iter()
+    .find_map(|(label, der)| signing_key(&label, der))
+    .unwrap_or_else(|| Err("contains no private key".into()))
+    .map_err(|reason| cert_error(format!("`{}` {}", key_path.display(), reason)))?;
+  check_key(&cert_chain[0], signing_key.as_ref())
+    .map_err(|reason| cert_error(format!("`{}` {}", key_path.display(), reason)))?;
+  check_expiry(hostname, &cert_chain[0]);
+
+  Ok(CertifiedKey::new(cert_chain, Arc::new(signing_key)))
+}
+
+/// Generate a certificate for `names` signed by its own throwaway key. Fine
+/// for development, no client will trust it.
+pub fn self_signed_certificate(names: Vec<String>) -> Result<CertifiedKey, Error> {
+  let cert_error = |reason: String| Error::Certificate {
+    domain: "default".into(),
+    reason,
+  };
+  let generate_error = |e: rcgen::RcgenError| cert_error(format!("could not be generated: {}", e));
+
+  let cert = rcgen::generate_simple_self_signed(names).map_err(generate_error)?;
+  let key = any_ecdsa_type(&PrivateKey(cert.serialize_private_key_der()))
+    .map_err(|()| cert_error("has a key rustls can't load".into()))?;
+  let chain = vec![Certificate(cert.serialize_der().map_err(generate_error)?)];
+  Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+/// The signing key in a PEM block, `None` if the block isn't a private key.
+fn signing_key(label: &str, der: Vec<u8>) -> Option<Result<Box<dyn SigningKey>, String>> {
+  let key = match label {
+    "PRIVATE KEY" => any_supported_type(&PrivateKey(der))
+      .map_err(|()| "is not an RSA, ECDSA or Ed25519 key".to_string()),
+    "RSA PRIVATE KEY" => RSASigningKey::new(&PrivateKey(der))
+      .map(|key| Box::new(key) as Box<dyn SigningKey>)
+      .map_err(|()| "is not a valid RSA key".to_string()),
+    "EC PRIVATE KEY" => x509::sec1_to_pkcs8(&der)
+      .into_iter()
+      .find_map(|der| any_ecdsa_type(&PrivateKey(der)).ok())
+      .ok_or_else(|| "is not a P-256 or P-384 key".to_string()),
+    "ENCRYPTED PRIVATE KEY" => Err("is encrypted, which isn't supported".to_string()),
+    _ => return None,
+  };
+  Some(key)
 }
 
-enum TlsListenerState {
-  Listening,
-  Accepting(Accept<TcpStream>),
+/// Check that `key` belongs to `cert` by verifying a signature made with it.
+fn check_key(cert: &Certificate, key: &dyn SigningKey) -> Result<(), String> {
+  let cert = webpki::EndEntityCert::from(&cert.0)
+    .map_err(|e| format!("belongs to an invalid certificate: {:?}", e))?;
+  let signer = key
+    .choose_scheme(&KEY_CHECK_SCHEMES)
+    .ok_or("is of an unsupported type")?;
+  let algorithm = match signer.get_scheme() {
+    SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+    _ => &webpki::ED25519,
+  };
+  let message = b"proxy key check";
+  let signature = signer
+    .sign(message)
+    .map_err(|e| format!("could not sign: {}", e))?;
+  cert
+    .verify_signature(algorithm, message, &signature)
+    .map_err(|_| "does not match the certificate".to_string())
+}
+
+fn check_expiry(hostname: &str, cert: &Certificate) {
+  let not_after = match x509::not_after(&cert.0) {
+    Some(not_after) => not_after,
+    None => {
+      warn!("could not read when the certificate for `{}` expires", hostname);
+      return;
+    }
+  };
+  match not_after.duration_since(SystemTime::now()) {
+    Err(_) => warn!("certificate for `{}` has expired", hostname),
+    Ok(left) if left < EXPIRY_WARNING => warn!(
+      "certificate for `{}` expires in {} days",
+      hostname,
+      left.as_secs() / (24 * 60 * 60)
+    ),
+    Ok(_) => {}
+  }
+}
+
+/// Accepts TCP connections continuously and drives up to `max_handshakes`
+/// TLS handshakes at once, so a slow client can't hold up everyone else.
+pub struct TlsListener {
+  listener: TcpListener,
+  acceptors: Arc<Acceptors>,
+  certificates: Arc<Resolver>,
+  challenges: Arc<Challenges>,
+  handshakes: FuturesUnordered<Timeout<BoxFuture<'static, io::Result<TlsConnection>>>>,
+  max_handshakes: usize,
+  handshake_timeout: Duration,
 }
 
 impl Listener for TlsListener {
-  type Connection = TlsStream<TcpStream>;
+  type Connection = TlsConnection;
 
-  fn local_addr(&self) -> Option<SocketAddr> {
-    self.listener.local_addr().ok()
+  fn local_addr(&self) -> Option<Address> {
+    self.listener.local_addr().ok().map(Address::Tcp)
   }
 
   fn poll_accept(
@@ -73,63 +192,529 @@ impl Listener for TlsListener {
     cx: &mut Context<'_>,
   ) -> Poll<io::Result<Self::Connection>> {
     loop {
-      match self.state {
-        TlsListenerState::Listening => match self.listener.poll_accept(cx) {
-          Poll::Pending => return Poll::Pending,
+      // Start handshakes for every waiting connection while there's room.
+      // When the limit is hit we rely on a finishing handshake to wake us.
+      while self.handshakes.len() < self.max_handshakes {
+        match self.listener.poll_accept(cx) {
+          Poll::Pending => break,
           Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-          Poll::Ready(Ok((stream, _addr))) => {
-            let fut = self.acceptor.accept(stream);
-            self.state = TlsListenerState::Accepting(fut);
+          Poll::Ready(Ok((stream, addr))) => {
+            let handshake = handshake(
+              self.acceptors.clone(),
+              TcpConnection::new(stream, addr),
+              self.certificates.clone(),
+              self.challenges.clone(),
+            );
+            let fut = timeout(self.handshake_timeout, handshake);
+            self.handshakes.push(fut);
           }
-        },
-        TlsListenerState::Accepting(ref mut fut) => match Pin::new(fut).poll(cx) {
-          Poll::Pending => return Poll::Pending,
-          Poll::Ready(result) => {
-            self.state = TlsListenerState::Listening;
-            return Poll::Ready(result);
+        }
+      }
+
+      match self.handshakes.poll_next_unpin(cx) {
+        Poll::Ready(Some(Ok(Ok(stream)))) => {
+          // The ACME server only checks the certificate, there's nothing to
+          // serve on the connection.
+          if stream.get_ref().1.get_alpn_protocol() != Some(ACME_TLS_ALPN) {
+            return Poll::Ready(Ok(stream));
           }
-        },
+          debug!("answered tls-alpn-01 challenge");
+        }
+        // A failed handshake only concerns that client, keep accepting.
+        Poll::Ready(Some(Ok(Err(e)))) => debug!("tls handshake failed: {}", e),
+        Poll::Ready(Some(Err(_))) => {
+          metrics::timed_out(crate::timeout::Timeout::Handshake);
+          debug!("tls handshake timed out")
+        }
+        Poll::Ready(None) | Poll::Pending => return Poll::Pending,
       }
     }
   }
 }
 
-pub async fn bind_tls(
-  address: SocketAddr,
-  resolver: rustls::ResolvesServerCertUsingSNI,
-) -> io::Result<TlsListener> {
-  let listener = TcpListener::bind(address).await?;
+/// The configs handshakes are accepted with, which only differ in how
+/// clients authenticate.
+struct Acceptors {
+  /// Without client authentication, for ACME validation.
+  plain: Arc<ServerConfig>,
+  default: Arc<ServerConfig>,
+  by_name: HashMap<String, Arc<ServerConfig>>,
+  /// Whether any of them authenticates clients.
+  client_auth: bool,
+}
 
-  let tls_cfg = {
-    // Load public certificate.
-    // Do not use client certificate authentication.
-    let mut cfg = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-    // Select a certificate to use.
-    cfg.cert_resolver = Arc::new(resolver);
+impl Acceptors {
+  fn pick(&self, hello: &Hello) -> &Arc<ServerConfig> {
+    if hello.acme_validation {
+      return &self.plain;
+    }
+    hello
+      .server_name
+      .as_ref()
+      .and_then(|name| self.by_name.get(name))
+      .unwrap_or(&self.default)
+  }
+}
+
+/// Hand `stream` to rustls with the config for the name the client asked
+/// for, unless unknown names are rejected and it asked for one, which gets
+/// an `unrecognized_name` alert instead.
+fn handshake(
+  acceptors: Arc<Acceptors>,
+  mut stream: TcpConnection,
+  certificates: Arc<Resolver>,
+  challenges: Arc<Challenges>,
+) -> BoxFuture<'static, io::Result<TlsConnection>> {
+  Box::pin(async move {
+    let reject_unknown_names = certificates.rejects_unknown_names();
+    let hello = if reject_unknown_names || acceptors.client_auth {
+      peek_client_hello(stream.get_ref()).await?
+    } else {
+      Hello::default()
+    };
+
+    if let Some(name) = hello.server_name.as_ref().filter(|_| reject_unknown_names) {
+      let known =
+        certificates.get(name).is_some() || challenges.tls_alpn_certificate(name).is_some();
+      if !known {
+        stream.write_all(&UNRECOGNIZED_NAME_ALERT).await?;
+        let _ = stream.shutdown().await;
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("no certificate for `{}`", name),
+        ));
+      }
+    }
+    let config = acceptors.pick(&hello);
+    let stream = TlsAcceptor::from(config.clone()).accept(stream).await?;
+
+    // What we peeked isn't necessarily what rustls read, e.g. when the
+    // ClientHello is split over several records. Only keep the connection
+    // if it was accepted with the config for what the client asked for.
+    let session = stream.get_ref().1;
+    let negotiated = Hello {
+      server_name: session.get_sni_hostname().map(str::to_ascii_lowercase),
+      acme_validation: session.get_alpn_protocol() == Some(ACME_TLS_ALPN),
+    };
+    if !Arc::ptr_eq(acceptors.pick(&negotiated), config) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "could not read the ClientHello for `{}` before the handshake",
+          negotiated.server_name.unwrap_or_default()
+        ),
+      ));
+    }
+    Ok(stream)
+  })
+}
+
+/// What a ClientHello asks for, as far as picking how to answer it goes.
+#[derive(Debug, Default, PartialEq)]
+struct Hello {
+  server_name: Option<String>,
+  /// Whether it only offers the TLS-ALPN-01 validation protocol, as ACME
+  /// servers do.
+  acme_validation: bool,
+}
+
+/// Read the ClientHello waiting on `stream` without taking it off the
+/// stream.
+async fn peek_client_hello(stream: &TcpStream) -> io::Result<Hello> {
+  // The header and the largest record allowed.
+  let mut buf = vec![0; 5 + (1 << 14)];
+  loop {
+    let n = stream.peek(&mut buf).await?;
+    if n == 0 {
+      return Ok(Hello::default());
+    }
+    match read_client_hello(&buf[..n]) {
+      Some(hello) => return Ok(hello),
+      // Peeking again returns at once with the same bytes, so give the rest
+      // a moment to arrive.
+      None if n < buf.len() => sleep(CLIENT_HELLO_WAIT).await,
+      None => return Ok(Hello::default()),
+    }
+  }
+}
 
-    cfg.ticketer = rustls::Ticketer::new();
-    let cache = rustls::ServerSessionMemoryCache::new(1024);
-    cfg.set_persistence(cache);
-    // Configure ALPN to accept HTTP/2, HTTP/1.1 in that order.
-    cfg.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
-    cfg
+/// The ClientHello in the record at the start of `data`, `None` if `data`
+/// doesn't hold the whole record yet. Anything that isn't a ClientHello we
+/// can read asks for nothing, and is left for rustls to turn down.
+fn read_client_hello(data: &[u8]) -> Option<Hello> {
+  let mut header = Reader(data);
+  let kind = header.u8()?;
+  header.take(2)?;
+  let len = header.u16()?;
+  if kind != HANDSHAKE_RECORD {
+    return Some(Hello::default());
+  }
+  let record = header.take(len)?;
+  Some(parse_client_hello(Reader(record)).unwrap_or_default())
+}
+
+fn parse_client_hello(mut record: Reader) -> Option<Hello> {
+  if record.u8()? != CLIENT_HELLO {
+    return None;
+  }
+  // A ClientHello split over several records is too rare to bother with.
+  let len = record.u24()?;
+  let mut body = Reader(record.take(len)?);
+  // Version and random, then the session id, cipher suites and compression
+  // methods.
+  body.take(2 + 32)?;
+  body.vec8()?;
+  body.vec16()?;
+  body.vec8()?;
+
+  let mut hello = Hello::default();
+  let mut extensions = Reader(body.vec16()?);
+  while !extensions.0.is_empty() {
+    let kind = extensions.u16()?;
+    let data = extensions.vec16()?;
+    match kind {
+      SERVER_NAME_EXTENSION => {
+        let mut names = Reader(Reader(data).vec16()?);
+        while !names.0.is_empty() {
+          let name_type = names.u8()?;
+          let name = names.vec16()?;
+          if name_type == HOST_NAME {
+            hello.server_name = std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+          }
+        }
+      }
+      ALPN_EXTENSION => {
+        let mut protocols = Reader(Reader(data).vec16()?);
+        let only = protocols.vec8()?;
+        hello.acme_validation = only == ACME_TLS_ALPN && protocols.0.is_empty();
+      }
+      _ => {}
+    }
+  }
+  Some(hello)
+}
+
+/// Reads the big-endian integers and length-prefixed vectors TLS messages
+/// are made of.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+    if self.0.len() < n {
+      return None;
+    }
+    let (taken, rest) = self.0.split_at(n);
+    self.0 = rest;
+    Some(taken)
+  }
+
+  fn uint(&mut self, bytes: usize) -> Option<usize> {
+    Some(self.take(bytes)?.iter().fold(0, |n, &b| n << 8 | usize::from(b)))
+  }
+
+  fn u8(&mut self) -> Option<u8> {
+    Some(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Option<usize> {
+    self.uint(2)
+  }
+
+  fn u24(&mut self) -> Option<usize> {
+    self.uint(3)
+  }
+
+  fn vec8(&mut self) -> Option<&'a [u8]> {
+    let len = self.uint(1)?;
+    self.take(len)
+  }
+
+  fn vec16(&mut self) -> Option<&'a [u8]> {
+    let len = self.u16()?;
+    self.take(len)
+  }
+}
+
+/// Answers TLS-ALPN-01 validation handshakes with the certificate of the
+/// challenge, and every other handshake from `resolver`.
+struct ChallengeResolver {
+  resolver: Arc<dyn ResolvesServerCert>,
+  challenges: Arc<Challenges>,
+}
+
+impl ResolvesServerCert for ChallengeResolver {
+  fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+    // ACME servers offer nothing else, see RFC 8737.
+    let validation = client_hello.alpn() == Some(&[ACME_TLS_ALPN][..]);
+    if validation {
+      let server_name = client_hello.server_name()?;
+      return self.challenges.tls_alpn_certificate(server_name.into());
+    }
+    self.resolver.resolve(client_hello)
+  }
+}
+
+/// Serve TLS on a TCP listener that is already bound, either by us or by
+/// whoever handed it over.
+pub fn bind_tls(
+  listener: TcpListener,
+  certificates: Arc<Resolver>,
+  challenges: Arc<Challenges>,
+  client_auth: ClientAuth,
+  max_handshakes: usize,
+  handshake_timeout: Duration,
+) -> TlsListener {
+  let resolver: Arc<dyn ResolvesServerCert> = Arc::new(ChallengeResolver {
+    resolver: certificates.clone(),
+    challenges: challenges.clone(),
+  });
+  let config = |verifier| server_config(resolver.clone(), verifier);
+  let plain = config(NoClientAuth::new());
+  let authenticates = client_auth.default.is_some() || !client_auth.by_name.is_empty();
+  let acceptors = Acceptors {
+    default: client_auth.default.clone().map_or_else(|| plain.clone(), config),
+    by_name: client_auth
+      .by_name
+      .into_iter()
+      .map(|(name, verifier)| (name, config(verifier)))
+      .collect(),
+    client_auth: authenticates,
+    plain,
   };
-  
-  let acceptor = TlsAcceptor::from(Arc::new(tls_cfg));
-  let state = TlsListenerState::Listening;
 
-  Ok(TlsListener {
+  TlsListener {
     listener,
-    acceptor,
-    state,
-  })
+    acceptors: Arc::new(acceptors),
+    certificates,
+    challenges,
+    handshakes: FuturesUnordered::new(),
+    max_handshakes,
+    handshake_timeout,
+  }
 }
 
-impl Connection for TlsStream<TcpStream> {
-  fn remote_addr(&self) -> SocketAddr {
-    self.get_ref().0.peer_addr().unwrap()
+/// Each config gets its own session cache and ticket keys, so a session
+/// can't be resumed under a config authenticating clients differently.
+fn server_config(
+  resolver: Arc<dyn ResolvesServerCert>,
+  verifier: Arc<dyn ClientCertVerifier>,
+) -> Arc<ServerConfig> {
+  let mut cfg = ServerConfig::new(verifier);
+  // Select a certificate to use.
+  cfg.cert_resolver = resolver;
+
+  cfg.ticketer = rustls::Ticketer::new();
+  let cache = rustls::ServerSessionMemoryCache::new(1024);
+  cfg.set_persistence(cache);
+  // Configure ALPN to accept HTTP/2, HTTP/1.1 in that order, and ACME
+  // validation handshakes.
+  cfg.set_protocols(&[
+    b"h2".to_vec(),
+    b"http/1.1".to_vec(),
+    ACME_TLS_ALPN.to_vec(),
+  ]);
+  Arc::new(cfg)
+}
+
+/// A TLS connection accepted by a [`TlsListener`].
+pub type TlsConnection = TlsStream<TcpConnection>;
+
+impl Connection for TlsConnection {
+  fn remote_addr(&self) -> Address {
+    self.get_ref().0.remote_addr()
   }
   fn sni_hostname(&self) -> Option<&str> {
     self.get_ref().1.get_sni_hostname()
   }
+  fn client_cert(&self) -> Option<ClientCert> {
+    let chain = self.get_ref().1.get_peer_certificates()?;
+    chain.first().map(|cert| ClientCert::new(&cert.0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    bind_tls, read_client_hello, self_signed_certificate, Hello, TlsListener, ACME_TLS_ALPN,
+  };
+  use crate::certs::{Certificates, Resolver};
+  use crate::client_auth::ClientAuth;
+  use crate::listener::Listener;
+  use futures_util::future::poll_fn;
+  use rustls::AllowAnyAuthenticatedClient;
+  use rustls_client::{ClientConfig, ClientConnection, RootCertStore, ServerName};
+  use std::convert::TryFrom;
+  use std::net::SocketAddr;
+  use std::pin::Pin;
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+  use tokio::io::AsyncReadExt;
+  use tokio::net::{TcpListener, TcpStream};
+  use tokio::sync::mpsc;
+  use tokio::time::{sleep, timeout};
+  use tokio_rustls_client::TlsConnector;
+
+  fn client_hello(name: &str, enable_sni: bool) -> Vec<u8> {
+    client_hello_offering(name, enable_sni, &[])
+  }
+
+  fn client_hello_offering(name: &str, enable_sni: bool, alpn: &[&[u8]]) -> Vec<u8> {
+    let mut config = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(RootCertStore::empty())
+      .with_no_client_auth();
+    config.enable_sni = enable_sni;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    let name = ServerName::try_from(name).unwrap();
+    let mut connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut hello = Vec::new();
+    connection.write_tls(&mut hello).unwrap();
+    hello
+  }
+
+  fn client_hello_server_name(data: &[u8]) -> Option<Option<String>> {
+    read_client_hello(data).map(|hello| hello.server_name)
+  }
+
+  #[test]
+  fn reads_server_name_from_client_hello() {
+    let hello = client_hello("WWW.example.com", true);
+    assert_eq!(
+      client_hello_server_name(&hello),
+      Some(Some("www.example.com".to_string()))
+    );
+    assert_eq!(client_hello_server_name(&hello[..hello.len() - 1]), None);
+    assert_eq!(client_hello_server_name(&hello[..3]), None);
+    assert_eq!(
+      client_hello_server_name(&client_hello("example.com", false)),
+      Some(None)
+    );
+    assert_eq!(client_hello_server_name(b"GET / HTTP/1.1\r\n"), Some(None));
+  }
+
+  #[test]
+  fn reads_acme_validation_from_client_hello() {
+    let hello = client_hello_offering("www.example.com", true, &[b"h2", b"http/1.1"]);
+    let www = Hello {
+      server_name: Some("www.example.com".to_string()),
+      acme_validation: false,
+    };
+    assert_eq!(read_client_hello(&hello), Some(www));
+    assert_eq!(read_client_hello(b"GET / HTTP/1.1\r\n"), Some(Hello::default()));
+
+    let validation = client_hello_offering("example.com", true, &[ACME_TLS_ALPN]);
+    assert!(read_client_hello(&validation).unwrap().acme_validation);
+    let mixed = client_hello_offering("example.com", true, &[ACME_TLS_ALPN, b"h2"]);
+    assert!(!read_client_hello(&mixed).unwrap().acme_validation);
+  }
+
+  /// A listener serving a self-signed certificate for `localhost`, the
+  /// handshakes it finished, and a client trusting that certificate.
+  async fn listener(
+    max_handshakes: usize,
+    handshake_timeout: Duration,
+  ) -> (SocketAddr, mpsc::UnboundedReceiver<()>, TlsConnector) {
+    let (addr, handshakes, config) =
+      authenticating_listener(ClientAuth::default(), max_handshakes, handshake_timeout).await;
+    (addr, handshakes, TlsConnector::from(Arc::new(config)))
+  }
+
+  /// Like `listener`, authenticating clients with `client_auth`, and the
+  /// client's config.
+  async fn authenticating_listener(
+    client_auth: ClientAuth,
+    max_handshakes: usize,
+    handshake_timeout: Duration,
+  ) -> (SocketAddr, mpsc::UnboundedReceiver<()>, ClientConfig) {
+    let key = self_signed_certificate(vec!["localhost".to_string()]).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(&rustls_client::Certificate(key.cert[0].0.clone())).unwrap();
+    let mut certificates = Certificates::default();
+    certificates.insert("localhost", key).unwrap();
+
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let mut listener: TlsListener = bind_tls(
+      tcp,
+      Arc::new(Resolver::new(certificates)),
+      Arc::default(),
+      client_auth,
+      max_handshakes,
+      handshake_timeout,
+    );
+    let (accepted, handshakes) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      while poll_fn(|cx| Pin::new(&mut listener).poll_accept(cx)).await.is_ok() {
+        let _ = accepted.send(());
+      }
+    });
+
+    let config = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    (addr, handshakes, config)
+  }
+
+  async fn connect(addr: SocketAddr, connector: &TlsConnector) {
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    connector.connect(name, tcp).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn drops_stalled_handshakes_without_blocking_others() {
+    let (addr, mut handshakes, connector) = listener(4, Duration::from_millis(300)).await;
+    let mut stalled = TcpStream::connect(addr).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let started = Instant::now();
+    connect(addr, &connector).await;
+    handshakes.recv().await.unwrap();
+    assert!(started.elapsed() < Duration::from_millis(200));
+
+    // The stalled client is disconnected once its time is up.
+    let closed = timeout(Duration::from_secs(2), stalled.read(&mut [0; 1])).await;
+    assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+  }
+
+  #[tokio::test]
+  async fn limits_concurrent_handshakes() {
+    let (addr, mut handshakes, connector) = listener(1, Duration::from_millis(300)).await;
+    let _stalled = TcpStream::connect(addr).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    // The only slot is taken until the stalled handshake times out.
+    let started = Instant::now();
+    let client = tokio::spawn(async move { connect(addr, &connector).await });
+    assert!(timeout(Duration::from_millis(150), handshakes.recv()).await.is_err());
+    handshakes.recv().await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    client.await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn authenticates_clients_whose_client_hello_comes_in_pieces() {
+    let ca = self_signed_certificate(vec!["ca.localhost".to_string()]).unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&ca.cert[0]).unwrap();
+    let mut client_auth = ClientAuth::default();
+    client_auth
+      .by_name
+      .insert("localhost".to_string(), AllowAnyAuthenticatedClient::new(roots));
+
+    for (client_auth, accepted) in [(ClientAuth::default(), true), (client_auth, false)] {
+      let (addr, mut handshakes, mut config) =
+        authenticating_listener(client_auth, 4, Duration::from_secs(1)).await;
+      // Small enough to split the ClientHello over several records.
+      config.max_fragment_size = Some(64);
+      let connector = TlsConnector::from(Arc::new(config));
+      let tcp = TcpStream::connect(addr).await.unwrap();
+      let name = ServerName::try_from("localhost").unwrap();
+      let _ = connector.connect(name, tcp).await;
+      let handshake = timeout(Duration::from_millis(300), handshakes.recv()).await;
+      assert_eq!(handshake.is_ok(), accepted);
+    }
+  }
 }
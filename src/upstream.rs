@@ -0,0 +1,205 @@
+use crate::config::{HealthCheckConfig, OutlierDetectionConfig, UpstreamConfig};
+use crate::connector::UpstreamTls;
+use crate::errors::Error;
+use hyper::Uri;
+use log::warn;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A named group of servers that requests for an upstream are spread over.
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub members: Vec<Arc<Member>>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    pub tls: Option<UpstreamTls>,
+}
+
+/// A single server of a pool.
+#[derive(Debug)]
+pub struct Member {
+    pub url: Uri,
+    pub weight: u32,
+    outstanding: AtomicUsize,
+    healthy: AtomicBool,
+    /// Failed requests since the last successful one.
+    failures: AtomicU32,
+    ejection: Mutex<Ejection>,
+}
+
+/// Passive health of a member, based on the requests sent to it.
+#[derive(Debug, Default)]
+struct Ejection {
+    /// When the member is let back in after its last ejection.
+    until: Option<Instant>,
+    /// Ejections in a row, each doubling the time the member stays out.
+    count: u32,
+}
+
+impl Pool {
+    /// Fails if the upstream's TLS files can't be loaded.
+    pub fn new(name: &str, config: &UpstreamConfig) -> Result<Pool, Error> {
+        let members = config
+            .servers()
+            .map(|(url, weight)| {
+                Arc::new(Member {
+                    url: url.clone(),
+                    weight,
+                    outstanding: AtomicUsize::new(0),
+                    healthy: AtomicBool::new(true),
+                    failures: AtomicU32::new(0),
+                    ejection: Mutex::default(),
+                })
+            })
+            .collect();
+
+        let tls = match &config.tls {
+            Some(tls) => Some(UpstreamTls::load(
+                name,
+                tls,
+                config.servers().map(|(url, _)| url),
+            )?),
+            None => None,
+        };
+
+        Ok(Pool {
+            name: name.to_string(),
+            members,
+            health_check: config.health_check.clone(),
+            outlier_detection: config.outlier_detection.clone(),
+            tls,
+        })
+    }
+
+    /// Record whether a request to `member` succeeded, and eject it for a
+    /// while after too many failures in a row, unless that would eject more
+    /// of the pool than allowed.
+    pub fn report(&self, member: &Member, success: bool) {
+        let config = match &self.outlier_detection {
+            Some(config) => config,
+            None => return,
+        };
+        if success {
+            member.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+        if member.failures.fetch_add(1, Ordering::Relaxed) + 1 < config.consecutive_failures {
+            return;
+        }
+
+        let ejected = self.members.iter().filter(|m| m.is_ejected()).count();
+        if (ejected + 1) * 100 > usize::from(config.max_ejected_percent) * self.members.len() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut ejection = member.ejection.lock().unwrap();
+        if ejection.until.is_some_and(|until| until > now) {
+            // Requests sent before the ejection are still failing.
+            return;
+        }
+        // A member that has behaved for a while starts over at the base time.
+        if ejection
+            .until
+            .is_none_or(|until| now - until > config.max_ejection_time)
+        {
+            ejection.count = 0;
+        }
+        ejection.count += 1;
+        let duration = config
+            .base_ejection_time
+            .saturating_mul(1 << (ejection.count - 1).min(16))
+            .min(config.max_ejection_time);
+        ejection.until = Some(now + duration);
+        member.failures.store(0, Ordering::Relaxed);
+
+        warn!(
+            "ejecting upstream `{}` server {} for {:?} after {} failures",
+            self.name, member.url, duration, config.consecutive_failures
+        );
+    }
+}
+
+impl Member {
+    /// Whether balancers may send requests to this member.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_ejected()
+    }
+
+    /// Whether the member is out of rotation after failing requests.
+    pub fn is_ejected(&self) -> bool {
+        self.ejection
+            .lock()
+            .unwrap()
+            .until
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// Whether the last health checks passed, or no checks are configured.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Requests sent to this member that haven't been answered yet.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Count a request as outstanding until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> InFlight {
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+}
+
+/// An outstanding request to a member, see [`Member::start_request`].
+pub struct InFlight(Arc<Member>);
+
+impl InFlight {
+    pub fn member(&self) -> &Arc<Member> {
+        &self.0
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pool;
+    use crate::config::UpstreamConfig;
+
+    #[test]
+    fn ejects_after_consecutive_failures() {
+        let config: UpstreamConfig = toml::from_str(
+            r#"
+            servers = [{ url = "http://a" }, { url = "http://b" }]
+            outlier_detection = { consecutive_failures = 2, max_ejected_percent = 50 }
+            "#,
+        )
+        .unwrap();
+        let pool = Pool::new("app", &config).unwrap();
+        let (a, b) = (&pool.members[0], &pool.members[1]);
+
+        pool.report(a, false);
+        pool.report(a, true);
+        pool.report(a, false);
+        assert!(a.is_available());
+        pool.report(a, false);
+        assert!(!a.is_available());
+
+        // Ejecting `b` as well would take out more than half the pool.
+        pool.report(b, false);
+        pool.report(b, false);
+        assert!(b.is_available());
+    }
+}
@@ -12,10 +12,8 @@ pub(crate) trait UriExt {
 
 impl UriExt for Uri {
     fn compute_redirect(&self, location: HeaderValue) -> Result<Uri, Error> {
-        let new_uri = http::Uri::from_maybe_shared(location)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
-        let old_uri = self.to_owned().into_parts();
-        let old_parts = http::uri::Parts::from(old_uri);
+        let new_uri = http::Uri::from_maybe_shared(location).map_err(io::Error::other)?;
+        let old_parts = self.to_owned().into_parts();
         let mut new_parts = http::uri::Parts::from(new_uri);
         if new_parts.scheme.is_none() {
             new_parts.scheme = old_parts.scheme;
@@ -23,8 +21,7 @@ impl UriExt for Uri {
         if new_parts.authority.is_none() {
             new_parts.authority = old_parts.authority;
         }
-        let absolute_new_uri = http::Uri::from_parts(new_parts)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let absolute_new_uri = http::Uri::from_parts(new_parts).map_err(io::Error::other)?;
         Ok(absolute_new_uri.to_string().parse::<Uri>()?)
     }
 
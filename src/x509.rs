@@ -0,0 +1,440 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::der_parser::asn1_rs::Tag;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{parse_x509_certificate, parse_x509_crl, X509Certificate, X509Name};
+use x509_parser::time::ASN1Time;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const SEQUENCE: u8 = 0x30;
+
+/// Short names of the attributes of distinguished names, by OID.
+const ATTRIBUTE_NAMES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x05], "serialNumber"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "STREET"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01],
+        "UID",
+    ),
+    (
+        &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19],
+        "DC",
+    ),
+    (
+        &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01],
+        "emailAddress",
+    ),
+];
+
+/// The `id-ecPublicKey` algorithm identifier, followed by the curve.
+const EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const P256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+/// The blocks of a PEM file as `(label, DER)`, e.g. `("CERTIFICATE", ..)`.
+/// Text outside of the blocks is ignored.
+pub fn pem_blocks(pem: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut blocks = Vec::new();
+    let mut lines = pem.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let label = match line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|line| line.strip_suffix("-----"))
+        {
+            Some(label) => label,
+            None => continue,
+        };
+        let end = format!("-----END {}-----", label);
+        let mut encoded = String::new();
+        loop {
+            match lines.next() {
+                Some(line) if line == end => break,
+                Some(line) => encoded.push_str(line),
+                None => return Err(format!("`{}` block is never closed", label)),
+            }
+        }
+        let der = base64::decode(&encoded)
+            .map_err(|e| format!("`{}` block is not valid base64: {}", label, e))?;
+        blocks.push((label.to_string(), der));
+    }
+    Ok(blocks)
+}
+
+/// Encode `der` as a PEM block.
+pub fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Wrap a SEC1 `EC PRIVATE KEY` in PKCS#8, once for every curve we can
+/// sign with. Only the one for the key's own curve will load.
+pub fn sec1_to_pkcs8(sec1: &[u8]) -> Vec<Vec<u8>> {
+    [P256, P384]
+        .iter()
+        .map(|curve| {
+            let algorithm = der(SEQUENCE, &[EC_PUBLIC_KEY, curve].concat());
+            let version = der(INTEGER, &[0]);
+            der(
+                SEQUENCE,
+                &[version, algorithm, der(OCTET_STRING, sec1)].concat(),
+            )
+        })
+        .collect()
+}
+
+fn certificate(der: &[u8]) -> Option<X509Certificate<'_>> {
+    parse_x509_certificate(der).ok().map(|(_, cert)| cert)
+}
+
+fn to_system_time(time: ASN1Time) -> Option<SystemTime> {
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(time.timestamp()).ok()?))
+}
+
+/// When a DER certificate stops being valid.
+pub fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    to_system_time(certificate(cert)?.validity().not_after)
+}
+
+/// The issuer of a DER certificate, as a whole DER name, and its serial
+/// number, the way CRLs list it.
+pub fn issuer_and_serial(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let cert = certificate(cert)?;
+    Some((cert.tbs_certificate.issuer.as_raw(), cert.raw_serial()))
+}
+
+/// The subject of a DER certificate in the RFC 4514 form, e.g.
+/// `CN=client,O=Example`.
+pub fn subject(cert: &[u8]) -> Option<String> {
+    name_to_string(certificate(cert)?.subject())
+}
+
+/// The DNS names, email addresses, URIs and IP addresses a DER certificate
+/// is issued for, e.g. `DNS:example.com` or `email:admin@example.com`.
+pub fn subject_alt_names(cert: &[u8]) -> Vec<String> {
+    let cert = match certificate(cert) {
+        Some(cert) => cert,
+        None => return Vec::new(),
+    };
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => &extension.value.general_names,
+        _ => return Vec::new(),
+    };
+    // IA5Strings are only passed on if they are printable.
+    let printable = |name: &str| name.bytes().all(|b| b.is_ascii_graphic());
+    names
+        .iter()
+        .filter_map(|name| match *name {
+            GeneralName::RFC822Name(name) if printable(name) => Some(format!("email:{}", name)),
+            GeneralName::DNSName(name) if printable(name) => Some(format!("DNS:{}", name)),
+            GeneralName::URI(name) if printable(name) => Some(format!("URI:{}", name)),
+            GeneralName::IPAddress(ip) => {
+                let ip = match ip.len() {
+                    4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+                    16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+                    _ => return None,
+                };
+                Some(format!("IP:{}", ip))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The issuer of a DER CRL, as a whole DER name, and the serial numbers of
+/// the certificates it revokes. The CRL must be signed by one of the DER
+/// `cas` and not be past its next update at `now`.
+pub fn crl_revocations(
+    crl: &[u8],
+    cas: &[Vec<u8>],
+    now: SystemTime,
+) -> Result<(Vec<u8>, Vec<Vec<u8>>), String> {
+    let (_, crl) = parse_x509_crl(crl).map_err(|e| format!("is not a valid CRL: {}", e))?;
+    let issuer = &crl.tbs_cert_list.issuer;
+    let mut issuers = cas
+        .iter()
+        .filter_map(|ca| certificate(ca))
+        .filter(|ca| ca.subject().as_raw() == issuer.as_raw())
+        .peekable();
+    if issuers.peek().is_none() {
+        return Err(format!(
+            "is issued by `{}`, which is not one of the CAs",
+            name_to_string(issuer).unwrap_or_default()
+        ));
+    }
+    if !issuers.any(|ca| crl.verify_signature(ca.public_key()).is_ok()) {
+        return Err("has a signature that doesn't match its issuer".to_string());
+    }
+    if let Some(next_update) = crl.next_update() {
+        if to_system_time(next_update).is_none_or(|next_update| next_update < now) {
+            return Err(format!("expired on {}", next_update));
+        }
+    }
+    let serials = crl
+        .iter_revoked_certificates()
+        .map(|revoked| revoked.raw_serial().to_vec())
+        .collect();
+    Ok((issuer.as_raw().to_vec(), serials))
+}
+
+/// A name in the RFC 4514 form: the last RDN first, characters with a
+/// meaning escaped, and bytes outside of printable ASCII as `\xx`.
+fn name_to_string(name: &X509Name) -> Option<String> {
+    let mut parts = name
+        .iter_rdn()
+        .map(|rdn| {
+            let attributes = rdn
+                .iter()
+                .map(|attribute| {
+                    let oid = attribute.attr_type();
+                    let name = match ATTRIBUTE_NAMES
+                        .iter()
+                        .find(|(known, _)| *known == oid.as_bytes())
+                    {
+                        Some((_, name)) => name.to_string(),
+                        None => oid.to_id_string(),
+                    };
+                    let value = attribute.attr_value();
+                    Some(format!(
+                        "{}={}",
+                        name,
+                        escape_value(&decode_string(value.tag(), value.data)?)
+                    ))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(attributes.join("+"))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    parts.reverse();
+    Some(parts.join(","))
+}
+
+fn decode_string(tag: Tag, value: &[u8]) -> Option<String> {
+    match tag {
+        Tag::BmpString => {
+            let pairs = value.chunks_exact(2);
+            if !pairs.remainder().is_empty() {
+                return None;
+            }
+            let units: Vec<u16> = pairs
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16(&units).ok()
+        }
+        Tag::Utf8String => String::from_utf8(value.to_vec()).ok(),
+        // PrintableString, IA5String and the like, which are ASCII, and the
+        // odd T61String, which usually is too.
+        _ => Some(value.iter().map(|&b| char::from(b)).collect()),
+    }
+}
+
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::new();
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i == last && c == ' ');
+        if special {
+            escaped.push('\\');
+            escaped.push(c);
+        } else if c.is_ascii_graphic() || c == ' ' {
+            escaped.push(c);
+        } else {
+            let mut utf8 = [0; 4];
+            for b in c.encode_utf8(&mut utf8).bytes() {
+                escaped.push_str(&format!("\\{:02x}", b));
+            }
+        }
+    }
+    escaped
+}
+
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        crl_revocations, der, issuer_and_serial, not_after, pem, pem_blocks, subject,
+        subject_alt_names, INTEGER, SEQUENCE,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, SanType};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const UTC_TIME: u8 = 0x17;
+
+    fn certificate_authority(name: &str) -> rcgen::Certificate {
+        let mut params = CertificateParams::new(Vec::new());
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    /// A DER CRL from `issuer` until `next_update`, revoking `serials`,
+    /// signed by `signer`.
+    fn crl(
+        issuer: &rcgen::Certificate,
+        signer: &rcgen::Certificate,
+        next_update: &[u8],
+        serials: &[&[u8]],
+    ) -> Vec<u8> {
+        let issuer = issuer.serialize_der().unwrap();
+        let (issuer, _) = issuer_and_serial(&issuer).unwrap();
+        let this_update = der(UTC_TIME, b"230101000000Z");
+        let revoked: Vec<u8> = serials
+            .iter()
+            .flat_map(|serial| {
+                der(
+                    SEQUENCE,
+                    &[der(INTEGER, serial), this_update.clone()].concat(),
+                )
+            })
+            .collect();
+        let algorithm = der(
+            SEQUENCE,
+            &der(0x06, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]),
+        );
+        let tbs = der(
+            SEQUENCE,
+            &[
+                der(INTEGER, &[1]),
+                algorithm.clone(),
+                issuer.to_vec(),
+                this_update.clone(),
+                der(UTC_TIME, next_update),
+                der(SEQUENCE, &revoked),
+            ]
+            .concat(),
+        );
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &signer.get_key_pair().serialize_der(),
+        )
+        .unwrap();
+        let signature = key.sign(&SystemRandom::new(), &tbs).unwrap();
+        let signature = der(0x03, &[&[0], signature.as_ref()].concat());
+        der(SEQUENCE, &[tbs, algorithm, signature].concat())
+    }
+
+    #[test]
+    fn reads_expiry_of_pem_certificate() {
+        let file = std::fs::read_to_string("cert.pem").unwrap();
+        let blocks = pem_blocks(&file).unwrap();
+        assert_eq!(blocks[0].0, "CERTIFICATE");
+        assert_eq!(
+            not_after(&blocks[0].1),
+            Some(UNIX_EPOCH + Duration::from_secs(1695692299))
+        );
+        assert!(pem_blocks("-----BEGIN CERTIFICATE-----\nAAAA").is_err());
+        assert_eq!(
+            pem_blocks(&pem(&blocks[0].0, &blocks[0].1)).unwrap(),
+            blocks
+        );
+    }
+
+    #[test]
+    fn reads_names_and_revocations() {
+        let mut params = CertificateParams::new(vec!["client.example".to_string()]);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "client, \"one\"");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Exämple");
+        params.subject_alt_names.extend(vec![
+            SanType::Rfc822Name("client@example.com".to_string()),
+            SanType::IpAddress("10.0.0.1".parse().unwrap()),
+        ]);
+        let ca = certificate_authority("Example CA");
+        let cert = rcgen::Certificate::from_params(params)
+            .unwrap()
+            .serialize_der_with_signer(&ca)
+            .unwrap();
+
+        assert_eq!(
+            subject(&cert).unwrap(),
+            "O=Ex\\c3\\a4mple,CN=client\\, \\\"one\\\""
+        );
+        assert_eq!(
+            subject_alt_names(&cert),
+            vec![
+                "DNS:client.example",
+                "email:client@example.com",
+                "IP:10.0.0.1"
+            ]
+        );
+
+        let (issuer, serial) = issuer_and_serial(&cert).unwrap();
+        let crl = crl(&ca, &ca, b"491231235959Z", &[&[5], serial]);
+        assert_eq!(
+            crl_revocations(&crl, &[ca.serialize_der().unwrap()], SystemTime::now()),
+            Ok((issuer.to_vec(), vec![vec![5], serial.to_vec()]))
+        );
+    }
+
+    #[test]
+    fn rejects_crls_not_from_a_ca() {
+        let ca = certificate_authority("Example CA");
+        let cas = [ca.serialize_der().unwrap()];
+        let now = SystemTime::now();
+        let revocations = |crl: &[u8]| crl_revocations(crl, &cas, now).map(|_| ());
+        assert_eq!(
+            revocations(&crl(&ca, &ca, b"491231235959Z", &[&[5]])),
+            Ok(())
+        );
+
+        let other = certificate_authority("Other CA");
+        let forged = crl(&ca, &other, b"491231235959Z", &[&[5]]);
+        assert_eq!(
+            revocations(&forged),
+            Err("has a signature that doesn't match its issuer".to_string())
+        );
+        let mut tampered = crl(&ca, &ca, b"491231235959Z", &[&[5]]);
+        let at = tampered
+            .windows(3)
+            .position(|w| w == [INTEGER, 1, 5])
+            .unwrap();
+        tampered[at + 2] = 6;
+        assert_eq!(
+            revocations(&tampered),
+            Err("has a signature that doesn't match its issuer".to_string())
+        );
+        assert_eq!(
+            revocations(&crl(&other, &other, b"491231235959Z", &[&[5]])),
+            Err("is issued by `CN=Other CA`, which is not one of the CAs".to_string())
+        );
+
+        let expired = crl(&ca, &ca, b"230201000000Z", &[&[5]]);
+        assert!(revocations(&expired).unwrap_err().starts_with("expired on"));
+        let before = UNIX_EPOCH + Duration::from_secs(1672617600);
+        assert!(crl_revocations(&expired, &cas, before).is_ok());
+    }
+}